@@ -7,26 +7,52 @@ Feature: divvun-api
     When I go to the endpoint `/languages`
     Then I get back a JSON object with available languages and their titles
 
+  Scenario: Checking the health of the server
+    When I go to the endpoint `/health`
+    Then I get back a JSON object with the status `ok`
+
+  Scenario: Checking the status of the loaded languages
+    When I go to the endpoint `/status`
+    Then I get back a JSON object listing `se` for every service
+
   Scenario: Checking spelling for `se` language
     When I go to the endpoint `/speller/se` with appropriate data
     Then I get back a SpellerResponse with suggestions for each word
 
+  Scenario: Checking spelling for `se` language returning only misspelled words
+    When I go to the endpoint `/speller/se` asking only for misspelled words
+    Then I get back a SpellerResponse with only the misspelled word
+
   Scenario: Checking grammar for `se` language
     When I go to the endpoint `/grammar/se` with appropriate data
     Then I get back a GramcheckResponse with `typo` and `double-space-before` error codes
 
+  Scenario: Checking grammar for multi-line text in `se` language
+    When I go to the endpoint `/grammar/se` with multi-line data
+    Then I get back a GramcheckResponse with errors offset to each line
+
+  Scenario: Checking grammar for `se` language while ignoring an error tag
+    When I go to the endpoint `/grammar/se` ignoring the `double-space-before` error tag
+    Then I get back a GramcheckResponse with only the `typo` error code
+
   Scenario: Checking spelling for not loaded `en` language
     When I go to the endpoint `/speller/en` for not loaded language
     Then I get back an ApiError with the message `No speller available for language en`
+    And the error has the status `404` and the code `language_not_found`
 
   Scenario: Checking grammar for not loaded `en` language
     When I go to the endpoint `/grammar/en` for not loaded language
     Then I get back an ApiError with the message `No grammar checker available for language en`
+    And the error has the status `404` and the code `language_not_found`
 
   Scenario: Checking hyphenation for `se` language
     When I go to the endpoint `/hyphenation/se` with appropriate data
     Then I get back the correct HyphenationResponse
 
+  Scenario: Checking a batch of texts
+    When I go to the endpoint `/batch` with texts for `se` and `en`
+    Then I get back a result for each item, with an error for `en`
+
   Scenario: Checking GraphQL response for `se` language
     When I go to the endpoint `/graphql` with an appropriate GraphQL query
     Then I get back a JSON object with a Speller and Grammar, and Hyphenation response
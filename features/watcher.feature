@@ -20,6 +20,7 @@ Feature: watcher
     When I remove the `se.zhfst` file from the `spelling` folder
     And I go to the endpoint `/speller/se` for not loaded language
     Then I get back an ApiError with the message `No speller available for language se`
+    And the error has the status `404` and the code `language_not_found`
     And I put the removed `se.zhfst` file back into the `spelling` folder
 
   Scenario: Retrieving grammar information for a language unloaded at runtime
@@ -27,6 +28,7 @@ Feature: watcher
     When I remove the `se.zcheck` file from the `grammar` folder
     And I go to the endpoint `/grammar/se` for not loaded language
     Then I get back an ApiError with the message `No grammar checker available for language se`
+    And the error has the status `404` and the code `language_not_found`
     And I put the removed `se.zcheck` file back into the `grammar` folder
 
   Scenario: Retrieving available languages for an ISO 639-3 language loaded at runtime
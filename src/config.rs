@@ -1,17 +1,952 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::Display;
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use actix_web::http::Method;
+use directories::ProjectDirs;
+use failure::Fail;
+use log::LevelFilter;
 use serde::Deserialize;
 
-use std::path::PathBuf;
+use crate::error::ApiError;
+use crate::language::data_files::DataFileType;
+
+/// Prefix of the environment variables overriding values from the config file
+const ENV_PREFIX: &str = "DIVVUN_API_";
+
+#[derive(Fail, Debug)]
+pub enum ConfigError {
+    #[fail(display = "Failed to read config file {}: {}", _0, _1)]
+    Read(String, io::Error),
+    #[fail(display = "Failed to parse config file {}: {}", _0, _1)]
+    Parse(String, toml::de::Error),
+    #[fail(display = "Invalid value for `{}`: {}", _0, _1)]
+    Invalid(String, String),
+}
+
+fn invalid<T>(name: &str, message: impl Display) -> Result<T, ConfigError> {
+    Err(ConfigError::Invalid(name.to_owned(), message.to_string()))
+}
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Default)]
 pub struct TomlConfig {
-    pub addr: String,
+    pub addr: Option<String>,
     pub data_file_dir: Option<PathBuf>,
+    pub workers: Option<usize>,
+    pub log_level: Option<String>,
     pub watcher_interval_ms: Option<u64>,
+    pub hyphenation_pool_size: Option<usize>,
+    pub grammar_pool_size: Option<usize>,
+    pub grammar_timeout_ms: Option<u64>,
+    pub grammar_restart_backoff_ms: Option<u64>,
+    pub grammar_max_restarts: Option<u32>,
+    #[serde(default)]
+    pub speller: SpellerSettings,
+    #[serde(default)]
+    pub cors: CorsSettings,
+    #[serde(default)]
+    pub limits: LimitSettings,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub services: ServiceSettings,
+    #[serde(default)]
+    pub languages: Languages,
+    #[serde(default)]
+    pub auth: AuthSettings,
 }
 
 #[derive(Clone)]
 pub struct Config {
     pub addr: String,
     pub data_file_dir: PathBuf,
+    pub workers: usize,
+    pub log_level: LevelFilter,
     pub watcher_interval_ms: u64,
+    pub hyphenation_pool_size: usize,
+    pub grammar_pool_size: usize,
+    pub grammar_timeout_ms: u64,
+    pub grammar_restart_backoff_ms: u64,
+    pub grammar_max_restarts: u32,
+    pub speller: SpellerSettings,
+    pub cors: CorsSettings,
+    pub limits: LimitSettings,
+    pub rate_limit: RateLimitSettings,
+    pub services: ServiceSettings,
+    pub languages: Languages,
+    pub auth: AuthSettings,
+}
+
+/// Server-side defaults and limits for the speller options of a request
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SpellerSettings {
+    pub max_suggestions: usize,
+    pub max_suggestions_limit: usize,
+    pub max_weight: Option<f32>,
+    pub max_weight_limit: Option<f32>,
+    pub beam: Option<f32>,
+    pub beam_limit: Option<f32>,
+    pub recase: bool,
+    pub suggest_correct: bool,
+}
+
+impl Default for SpellerSettings {
+    fn default() -> Self {
+        SpellerSettings {
+            max_suggestions: 10,
+            max_suggestions_limit: 50,
+            max_weight: None,
+            max_weight_limit: None,
+            beam: None,
+            beam_limit: None,
+            recase: true,
+            suggest_correct: true,
+        }
+    }
+}
+
+impl SpellerSettings {
+    pub fn with_overrides(&self, overrides: &SpellerOverrides) -> SpellerSettings {
+        SpellerSettings {
+            max_suggestions: overrides.max_suggestions.unwrap_or(self.max_suggestions),
+            max_suggestions_limit: overrides
+                .max_suggestions_limit
+                .unwrap_or(self.max_suggestions_limit),
+            max_weight: overrides.max_weight.or(self.max_weight),
+            max_weight_limit: overrides.max_weight_limit.or(self.max_weight_limit),
+            beam: overrides.beam.or(self.beam),
+            beam_limit: overrides.beam_limit.or(self.beam_limit),
+            recase: overrides.recase.unwrap_or(self.recase),
+            suggest_correct: overrides.suggest_correct.unwrap_or(self.suggest_correct),
+        }
+    }
+}
+
+/// Speller settings of a single language, falling back to the `[speller]` section
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SpellerOverrides {
+    pub max_suggestions: Option<usize>,
+    pub max_suggestions_limit: Option<usize>,
+    pub max_weight: Option<f32>,
+    pub max_weight_limit: Option<f32>,
+    pub beam: Option<f32>,
+    pub beam_limit: Option<f32>,
+    pub recase: Option<bool>,
+    pub suggest_correct: Option<bool>,
+}
+
+/// Settings of a single language, from its `[languages.<code>]` section
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LanguageSettings {
+    /// Data files of disabled languages are not loaded
+    pub enabled: bool,
+    /// Name listed by `/languages` instead of the autonym
+    pub display_name: Option<String>,
+    pub speller: SpellerOverrides,
+    /// Name of the divvun-checker pipeline to run instead of the default one
+    pub grammar_pipeline: Option<String>,
+    pub grammar_pool_size: Option<usize>,
+    /// Minimum number of characters before the first hyphenation point
+    pub hyphenation_min_left: Option<usize>,
+    /// Minimum number of characters after the last hyphenation point
+    pub hyphenation_min_right: Option<usize>,
+}
+
+impl Default for LanguageSettings {
+    fn default() -> Self {
+        LanguageSettings {
+            enabled: true,
+            display_name: None,
+            speller: SpellerOverrides::default(),
+            grammar_pipeline: None,
+            grammar_pool_size: None,
+            hyphenation_min_left: None,
+            hyphenation_min_right: None,
+        }
+    }
+}
+
+/// Language settings by language code
+pub type Languages = HashMap<String, LanguageSettings>;
+
+/// Returns the settings of the language, or the defaults if it has none
+pub fn language_settings(languages: &Languages, language: &str) -> LanguageSettings {
+    languages.get(language).cloned().unwrap_or_default()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CorsSettings {
+    /// Origins allowed to make cross-origin requests, `*` allows any origin
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub max_age_secs: usize,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        CorsSettings {
+            allowed_origins: vec!["*".to_owned()],
+            allowed_methods: vec!["GET".to_owned(), "POST".to_owned()],
+            max_age_secs: 3600,
+        }
+    }
+}
+
+impl CorsSettings {
+    pub fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == "*")
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LimitSettings {
+    /// Maximum size of a JSON request body
+    pub json_body_bytes: usize,
+    /// Maximum length of the text of a speller request, in characters
+    pub speller_max_chars: Option<usize>,
+    pub grammar_max_chars: Option<usize>,
+    pub hyphenation_max_chars: Option<usize>,
+    /// Maximum size of a `/batch` request body
+    pub batch_body_bytes: usize,
+    pub batch_max_items: usize,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        LimitSettings {
+            json_body_bytes: 32_768,
+            speller_max_chars: None,
+            grammar_max_chars: None,
+            hyphenation_max_chars: None,
+            batch_body_bytes: 1_048_576,
+            batch_max_items: 1000,
+        }
+    }
+}
+
+impl LimitSettings {
+    /// Checks the text of a request to the service against the service's length limit
+    pub fn check_text(&self, service: &str, text: &str) -> Result<(), ApiError> {
+        let limit = match service {
+            "speller" => self.speller_max_chars,
+            "grammar" => self.grammar_max_chars,
+            "hyphenation" => self.hyphenation_max_chars,
+            _ => None,
+        };
+
+        match limit {
+            Some(limit) if text.chars().count() > limit => Err(ApiError::PayloadTooLarge(format!(
+                "The text is longer than the {} characters allowed by the {} service",
+                limit, service
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Rate limiting of requests by client IP address
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    pub enabled: bool,
+    pub requests_per_minute: u32,
+    /// Number of requests a client can make at once before being limited
+    pub burst: u32,
+    /// Proxies whose `X-Forwarded-For` header is trusted to name the client
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            enabled: false,
+            requests_per_minute: 120,
+            burst: 20,
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+/// Which services are loaded and served
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ServiceSettings {
+    pub speller: bool,
+    pub grammar: bool,
+    pub hyphenation: bool,
+}
+
+impl Default for ServiceSettings {
+    fn default() -> Self {
+        ServiceSettings {
+            speller: true,
+            grammar: true,
+            hyphenation: true,
+        }
+    }
+}
+
+impl ServiceSettings {
+    pub fn enabled(&self, data_type: DataFileType) -> bool {
+        match data_type {
+            DataFileType::Spelling => self.speller,
+            DataFileType::Grammar => self.grammar,
+            DataFileType::Hyphenation => self.hyphenation,
+        }
+    }
+}
+
+/// Names of the services API keys can be restricted to, `status` opening the status endpoint
+pub const SERVICES: &[&str] = &["speller", "grammar", "hyphenation", "status"];
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AuthSettings {
+    /// Whether requests to the language services need an API key
+    pub enabled: bool,
+    /// API keys by client name
+    pub keys: HashMap<String, ApiKeySettings>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKeySettings {
+    pub key: String,
+    /// Languages the key can be used for, any language if empty
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// Services the key can be used for, any service if empty
+    #[serde(default)]
+    pub services: Vec<String>,
+    pub requests_per_minute: Option<u32>,
+    /// Number of requests allowed per UTC day
+    pub daily_quota: Option<u64>,
+}
+
+fn env_var(name: &str) -> Option<(String, String)> {
+    let key = format!("{}{}", ENV_PREFIX, name);
+    env::var(&key).ok().map(|value| (key, value))
+}
+
+fn parse_env<T>(name: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env_var(name) {
+        Some((key, value)) => match value.trim().parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => invalid(&key, format!("`{}`: {}", value, e)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn list_env(name: &str) -> Option<Vec<String>> {
+    env_var(name).map(|(_, value)| {
+        value
+            .split(',')
+            .map(|item| item.trim().to_owned())
+            .filter(|item| !item.is_empty())
+            .collect()
+    })
+}
+
+impl TomlConfig {
+    /// Overrides values from the config file with the matching `DIVVUN_API_*` environment
+    /// variables, e.g. `DIVVUN_API_ADDR` or `DIVVUN_API_GRAMMAR_POOL_SIZE`
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some((_, addr)) = env_var("ADDR") {
+            self.addr = Some(addr);
+        }
+        if let Some((_, dir)) = env_var("DATA_FILE_DIR") {
+            self.data_file_dir = Some(PathBuf::from(dir));
+        }
+        if let Some((_, level)) = env_var("LOG_LEVEL") {
+            self.log_level = Some(level);
+        }
+
+        self.workers = parse_env("WORKERS")?.or(self.workers);
+        self.watcher_interval_ms = parse_env("WATCHER_INTERVAL_MS")?.or(self.watcher_interval_ms);
+        self.hyphenation_pool_size =
+            parse_env("HYPHENATION_POOL_SIZE")?.or(self.hyphenation_pool_size);
+        self.grammar_pool_size = parse_env("GRAMMAR_POOL_SIZE")?.or(self.grammar_pool_size);
+        self.grammar_timeout_ms = parse_env("GRAMMAR_TIMEOUT_MS")?.or(self.grammar_timeout_ms);
+        self.grammar_restart_backoff_ms =
+            parse_env("GRAMMAR_RESTART_BACKOFF_MS")?.or(self.grammar_restart_backoff_ms);
+        self.grammar_max_restarts =
+            parse_env("GRAMMAR_MAX_RESTARTS")?.or(self.grammar_max_restarts);
+
+        if let Some(origins) = list_env("CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = origins;
+        }
+        if let Some(methods) = list_env("CORS_ALLOWED_METHODS") {
+            self.cors.allowed_methods = methods;
+        }
+        if let Some(max_age) = parse_env("CORS_MAX_AGE_SECS")? {
+            self.cors.max_age_secs = max_age;
+        }
+
+        if let Some(limit) = parse_env("JSON_BODY_BYTES")? {
+            self.limits.json_body_bytes = limit;
+        }
+        if let Some(limit) = parse_env("BATCH_BODY_BYTES")? {
+            self.limits.batch_body_bytes = limit;
+        }
+        if let Some(limit) = parse_env("BATCH_MAX_ITEMS")? {
+            self.limits.batch_max_items = limit;
+        }
+
+        self.limits.speller_max_chars =
+            parse_env("SPELLER_MAX_CHARS")?.or(self.limits.speller_max_chars);
+        self.limits.grammar_max_chars =
+            parse_env("GRAMMAR_MAX_CHARS")?.or(self.limits.grammar_max_chars);
+        self.limits.hyphenation_max_chars =
+            parse_env("HYPHENATION_MAX_CHARS")?.or(self.limits.hyphenation_max_chars);
+
+        if let Some(enabled) = parse_env("RATE_LIMIT_ENABLED")? {
+            self.rate_limit.enabled = enabled;
+        }
+        if let Some(requests) = parse_env("RATE_LIMIT_REQUESTS_PER_MINUTE")? {
+            self.rate_limit.requests_per_minute = requests;
+        }
+        if let Some(burst) = parse_env("RATE_LIMIT_BURST")? {
+            self.rate_limit.burst = burst;
+        }
+        if let Some((key, proxies)) = env_var("TRUSTED_PROXIES") {
+            self.rate_limit.trusted_proxies = proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| {
+                    proxy
+                        .parse()
+                        .or_else(|e| invalid(&key, format!("`{}`: {}", proxy, e)))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let Some(enabled) = parse_env("AUTH_ENABLED")? {
+            self.auth.enabled = enabled;
+        }
+
+        if let Some(enabled) = parse_env("SPELLER_ENABLED")? {
+            self.services.speller = enabled;
+        }
+        if let Some(enabled) = parse_env("GRAMMAR_ENABLED")? {
+            self.services.grammar = enabled;
+        }
+        if let Some(enabled) = parse_env("HYPHENATION_ENABLED")? {
+            self.services.hyphenation = enabled;
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Fills in defaults for everything not set in the config file and validates the result
+    pub fn from_toml(toml_config: TomlConfig) -> Result<Config, ConfigError> {
+        let addr = match toml_config.addr {
+            Some(addr) => addr,
+            None => return invalid("addr", "no bind address set, set `addr` in the config file"),
+        };
+        if let Err(e) = addr.to_socket_addrs() {
+            return invalid("addr", format!("`{}` is not a valid address: {}", addr, e));
+        }
+
+        let data_file_dir = match toml_config.data_file_dir {
+            Some(dir) => dir,
+            None => match ProjectDirs::from("no", "uit", "api-giellalt") {
+                Some(v) => v.data_dir().to_owned(),
+                None => PathBuf::from("./"),
+            },
+        };
+        if !data_file_dir.is_dir() {
+            return invalid(
+                "data_file_dir",
+                format!("`{}` is not a directory", data_file_dir.display()),
+            );
+        }
+
+        let log_level = toml_config.log_level.unwrap_or_else(|| "info".to_owned());
+        let log_level = match LevelFilter::from_str(&log_level) {
+            Ok(level) => level,
+            Err(_) => {
+                return invalid(
+                    "log_level",
+                    format!(
+                        "`{}` is not one of off, error, warn, info, debug or trace",
+                        log_level
+                    ),
+                )
+            }
+        };
+
+        let config = Config {
+            addr,
+            data_file_dir,
+            workers: toml_config.workers.unwrap_or(4),
+            log_level,
+            watcher_interval_ms: toml_config.watcher_interval_ms.unwrap_or(1000),
+            hyphenation_pool_size: toml_config.hyphenation_pool_size.unwrap_or(2),
+            grammar_pool_size: toml_config.grammar_pool_size.unwrap_or(2),
+            grammar_timeout_ms: toml_config.grammar_timeout_ms.unwrap_or(10_000),
+            grammar_restart_backoff_ms: toml_config.grammar_restart_backoff_ms.unwrap_or(500),
+            grammar_max_restarts: toml_config.grammar_max_restarts.unwrap_or(5),
+            speller: toml_config.speller,
+            cors: toml_config.cors,
+            limits: toml_config.limits,
+            rate_limit: toml_config.rate_limit,
+            services: toml_config.services,
+            languages: toml_config.languages,
+            auth: toml_config.auth,
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn language_enabled(&self, language: &str) -> bool {
+        self.languages
+            .get(language)
+            .map(|settings| settings.enabled)
+            .unwrap_or(true)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (name, value) in &[
+            ("workers", self.workers as u64),
+            ("watcher_interval_ms", self.watcher_interval_ms),
+            ("hyphenation_pool_size", self.hyphenation_pool_size as u64),
+            ("grammar_pool_size", self.grammar_pool_size as u64),
+            ("grammar_timeout_ms", self.grammar_timeout_ms),
+            (
+                "grammar_restart_backoff_ms",
+                self.grammar_restart_backoff_ms,
+            ),
+            ("limits.json_body_bytes", self.limits.json_body_bytes as u64),
+            (
+                "limits.batch_body_bytes",
+                self.limits.batch_body_bytes as u64,
+            ),
+            ("limits.batch_max_items", self.limits.batch_max_items as u64),
+            (
+                "rate_limit.requests_per_minute",
+                u64::from(self.rate_limit.requests_per_minute),
+            ),
+            ("rate_limit.burst", u64::from(self.rate_limit.burst)),
+        ] {
+            if *value == 0 {
+                return invalid(name, "must be greater than 0");
+            }
+        }
+
+        for (name, limit) in &[
+            ("limits.speller_max_chars", self.limits.speller_max_chars),
+            ("limits.grammar_max_chars", self.limits.grammar_max_chars),
+            (
+                "limits.hyphenation_max_chars",
+                self.limits.hyphenation_max_chars,
+            ),
+        ] {
+            if *limit == Some(0) {
+                return invalid(name, "must be greater than 0");
+            }
+        }
+
+        if self.speller.max_suggestions > self.speller.max_suggestions_limit {
+            return invalid(
+                "speller.max_suggestions",
+                format!(
+                    "{} is above speller.max_suggestions_limit ({})",
+                    self.speller.max_suggestions, self.speller.max_suggestions_limit
+                ),
+            );
+        }
+
+        if self.cors.allowed_origins.is_empty() {
+            return invalid(
+                "cors.allowed_origins",
+                "at least one origin, or `*`, is required",
+            );
+        }
+
+        for method in &self.cors.allowed_methods {
+            if Method::from_bytes(method.as_bytes()).is_err() {
+                return invalid(
+                    "cors.allowed_methods",
+                    format!("`{}` is not an HTTP method", method),
+                );
+            }
+        }
+
+        for (code, language) in &self.languages {
+            let name = |field: &str| format!("languages.{}.{}", code, field);
+
+            if language.grammar_pool_size == Some(0) {
+                return invalid(&name("grammar_pool_size"), "must be greater than 0");
+            }
+
+            if let Some(pipeline) = &language.grammar_pipeline {
+                if pipeline.trim().is_empty() {
+                    return invalid(&name("grammar_pipeline"), "must not be empty");
+                }
+            }
+
+            let speller = self.speller.with_overrides(&language.speller);
+            if speller.max_suggestions > speller.max_suggestions_limit {
+                return invalid(
+                    &name("speller.max_suggestions"),
+                    format!(
+                        "{} is above the max_suggestions_limit ({})",
+                        speller.max_suggestions, speller.max_suggestions_limit
+                    ),
+                );
+            }
+        }
+
+        if self.auth.enabled && self.auth.keys.is_empty() {
+            return invalid(
+                "auth.keys",
+                "at least one key is required when auth is enabled",
+            );
+        }
+
+        let mut keys = HashSet::new();
+        for (client, key) in &self.auth.keys {
+            let name = |field: &str| format!("auth.keys.{}.{}", client, field);
+
+            if key.key.trim().is_empty() {
+                return invalid(&name("key"), "must not be empty");
+            }
+            if !keys.insert(&key.key) {
+                return invalid(&name("key"), "the same key is used by another client");
+            }
+            if let Some(service) = key
+                .services
+                .iter()
+                .find(|service| !SERVICES.contains(&service.as_str()))
+            {
+                return invalid(
+                    &name("services"),
+                    format!("`{}` is not one of {}", service, SERVICES.join(", ")),
+                );
+            }
+            if key.requests_per_minute == Some(0) {
+                return invalid(&name("requests_per_minute"), "must be greater than 0");
+            }
+            if key.daily_quota == Some(0) {
+                return invalid(&name("daily_quota"), "must be greater than 0");
+            }
+        }
+
+        if !(self.services.speller || self.services.grammar || self.services.hyphenation) {
+            return invalid("services", "at least one service has to be enabled");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lazy_static::lazy_static;
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    lazy_static! {
+        /// The environment is shared by the tests running at the same time
+        static ref ENV: Mutex<()> = Mutex::new(());
+    }
+
+    /// Applies the environment variables to the config, removing them again afterwards
+    fn apply_env(toml_config: &mut TomlConfig, vars: &[(&str, &str)]) -> Result<(), ConfigError> {
+        let _env = ENV.lock();
+
+        for (name, value) in vars {
+            env::set_var(format!("{}{}", ENV_PREFIX, name), value);
+        }
+        let result = toml_config.apply_env();
+        for (name, _) in vars {
+            env::remove_var(format!("{}{}", ENV_PREFIX, name));
+        }
+
+        result
+    }
+
+    fn from_toml(toml: &str) -> Result<Config, ConfigError> {
+        let mut toml_config: TomlConfig = toml::from_str(toml).unwrap();
+        toml_config.addr = toml_config
+            .addr
+            .or_else(|| Some("127.0.0.1:8080".to_owned()));
+        toml_config.data_file_dir = toml_config.data_file_dir.or_else(|| Some(".".into()));
+
+        Config::from_toml(toml_config)
+    }
+
+    fn invalid_name(result: Result<Config, ConfigError>) -> String {
+        match result {
+            Err(ConfigError::Invalid(name, _)) => name,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("the config is valid"),
+        }
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut toml_config: TomlConfig = toml::from_str(
+            r#"
+            addr = "0.0.0.0:8080"
+            workers = 2
+            grammar_pool_size = 3
+
+            [limits]
+            speller_max_chars = 100
+            "#,
+        )
+        .unwrap();
+
+        apply_env(
+            &mut toml_config,
+            &[
+                ("ADDR", "127.0.0.1:9090"),
+                ("WORKERS", " 8 "),
+                (
+                    "CORS_ALLOWED_ORIGINS",
+                    "https://a.example, ,https://b.example",
+                ),
+                ("TRUSTED_PROXIES", "10.0.0.1,::1"),
+                ("RATE_LIMIT_ENABLED", "true"),
+                ("GRAMMAR_ENABLED", "false"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(toml_config.addr.as_deref(), Some("127.0.0.1:9090"));
+        assert_eq!(toml_config.workers, Some(8));
+        assert_eq!(toml_config.grammar_pool_size, Some(3));
+        assert_eq!(toml_config.limits.speller_max_chars, Some(100));
+        assert_eq!(
+            toml_config.cors.allowed_origins,
+            vec!["https://a.example", "https://b.example"]
+        );
+        assert_eq!(
+            toml_config.rate_limit.trusted_proxies,
+            vec![
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse::<IpAddr>().unwrap()
+            ]
+        );
+        assert!(toml_config.rate_limit.enabled);
+        assert!(!toml_config.services.grammar);
+    }
+
+    #[test]
+    fn test_apply_invalid_env() {
+        for (name, value) in &[
+            ("WORKERS", "many"),
+            ("GRAMMAR_TIMEOUT_MS", "-1"),
+            ("RATE_LIMIT_ENABLED", "yes"),
+            ("TRUSTED_PROXIES", "10.0.0.1,proxy.example"),
+        ] {
+            let mut toml_config = TomlConfig::default();
+
+            match apply_env(&mut toml_config, &[(*name, *value)]) {
+                Err(ConfigError::Invalid(key, _)) => {
+                    assert_eq!(key, format!("{}{}", ENV_PREFIX, name))
+                }
+                _ => panic!("{}={} was accepted", name, value),
+            }
+        }
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = from_toml("").unwrap();
+
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.log_level, LevelFilter::Info);
+        assert_eq!(config.grammar_timeout_ms, 10_000);
+    }
+
+    #[test]
+    fn test_missing_addr() {
+        let toml_config = TomlConfig {
+            data_file_dir: Some(".".into()),
+            ..Default::default()
+        };
+
+        assert_eq!(invalid_name(Config::from_toml(toml_config)), "addr");
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        for (toml, name) in &[
+            (r#"addr = "nowhere""#, "addr"),
+            (r#"data_file_dir = "Cargo.toml""#, "data_file_dir"),
+            (r#"log_level = "loud""#, "log_level"),
+            ("workers = 0", "workers"),
+            ("watcher_interval_ms = 0", "watcher_interval_ms"),
+            ("hyphenation_pool_size = 0", "hyphenation_pool_size"),
+            ("grammar_pool_size = 0", "grammar_pool_size"),
+            ("grammar_timeout_ms = 0", "grammar_timeout_ms"),
+            (
+                "grammar_restart_backoff_ms = 0",
+                "grammar_restart_backoff_ms",
+            ),
+            ("[limits]\njson_body_bytes = 0", "limits.json_body_bytes"),
+            ("[limits]\nbatch_body_bytes = 0", "limits.batch_body_bytes"),
+            ("[limits]\nbatch_max_items = 0", "limits.batch_max_items"),
+            (
+                "[limits]\nspeller_max_chars = 0",
+                "limits.speller_max_chars",
+            ),
+            (
+                "[limits]\ngrammar_max_chars = 0",
+                "limits.grammar_max_chars",
+            ),
+            (
+                "[limits]\nhyphenation_max_chars = 0",
+                "limits.hyphenation_max_chars",
+            ),
+            (
+                "[rate_limit]\nrequests_per_minute = 0",
+                "rate_limit.requests_per_minute",
+            ),
+            ("[rate_limit]\nburst = 0", "rate_limit.burst"),
+            (
+                "[speller]\nmax_suggestions = 60\nmax_suggestions_limit = 50",
+                "speller.max_suggestions",
+            ),
+            ("[cors]\nallowed_origins = []", "cors.allowed_origins"),
+            (
+                r#"[cors]
+                allowed_methods = ["GET", "NOT A METHOD"]"#,
+                "cors.allowed_methods",
+            ),
+            (
+                "[languages.se]\ngrammar_pool_size = 0",
+                "languages.se.grammar_pool_size",
+            ),
+            (
+                r#"[languages.se]
+                grammar_pipeline = " ""#,
+                "languages.se.grammar_pipeline",
+            ),
+            (
+                "[languages.se.speller]\nmax_suggestions_limit = 5",
+                "languages.se.speller.max_suggestions",
+            ),
+            ("[auth]\nenabled = true", "auth.keys"),
+            (
+                r#"[auth.keys.app]
+                key = " ""#,
+                "auth.keys.app.key",
+            ),
+            (
+                r#"[auth.keys.app]
+                key = "secret"
+                services = ["speller", "spelling"]"#,
+                "auth.keys.app.services",
+            ),
+            (
+                r#"[auth.keys.app]
+                key = "secret"
+                requests_per_minute = 0"#,
+                "auth.keys.app.requests_per_minute",
+            ),
+            (
+                r#"[auth.keys.app]
+                key = "secret"
+                daily_quota = 0"#,
+                "auth.keys.app.daily_quota",
+            ),
+            (
+                "[services]\nspeller = false\ngrammar = false\nhyphenation = false",
+                "services",
+            ),
+        ] {
+            assert_eq!(invalid_name(from_toml(toml)), *name, "{}", toml);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_key() {
+        let result = from_toml(
+            r#"
+            [auth.keys.app]
+            key = "secret"
+
+            [auth.keys.other]
+            key = "secret"
+            "#,
+        );
+
+        match result {
+            Err(ConfigError::Invalid(name, message)) => {
+                assert!(name == "auth.keys.app.key" || name == "auth.keys.other.key");
+                assert!(message.contains("another client"));
+            }
+            _ => panic!("the same key was accepted twice"),
+        }
+    }
+
+    #[test]
+    fn test_status_key() {
+        let config = from_toml(
+            r#"
+            [auth]
+            enabled = true
+
+            [auth.keys.monitoring]
+            key = "secret"
+            services = ["status"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.auth.keys["monitoring"].services, vec!["status"]);
+    }
+
+    #[test]
+    fn test_language_overrides() {
+        let config = from_toml(
+            r#"
+            [speller]
+            max_suggestions = 10
+            beam = 5.0
+            recase = true
+
+            [languages.se.speller]
+            max_suggestions = 3
+            recase = false
+            "#,
+        )
+        .unwrap();
+
+        let se = config
+            .speller
+            .with_overrides(&language_settings(&config.languages, "se").speller);
+        assert_eq!(se.max_suggestions, 3);
+        assert!(!se.recase);
+        assert_eq!(se.beam, Some(5.0));
+        assert_eq!(se.max_suggestions_limit, 50);
+
+        let sma = config
+            .speller
+            .with_overrides(&language_settings(&config.languages, "sma").speller);
+        assert_eq!(sma.max_suggestions, 10);
+        assert!(sma.recase);
+        assert!(config.language_enabled("sma"));
+    }
 }
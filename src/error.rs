@@ -1,39 +1,134 @@
 use std::io;
+use std::time::Duration;
 
 use actix_web::error::ResponseError;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
 use actix_web::HttpResponse;
 use failure::Fail;
-use log::error;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 
-#[derive(Fail, Debug, Deserialize, Serialize, Clone)]
-#[fail(display = "api error")]
-pub struct ApiError {
+#[derive(Fail, Debug, Clone)]
+pub enum ApiError {
+    #[fail(display = "{}", _0)]
+    LanguageNotFound(String),
+    #[fail(display = "{}", _0)]
+    InvalidInput(String),
+    #[fail(display = "{}", _0)]
+    Unauthorized(String),
+    #[fail(display = "{}", _0)]
+    Forbidden(String),
+    #[fail(display = "{}", _0)]
+    PayloadTooLarge(String),
+    /// Along with how long the client should wait before retrying, if known
+    #[fail(display = "{}", _0)]
+    TooManyRequests(String, Option<Duration>),
+    #[fail(display = "{}", _0)]
+    BackendUnavailable(String),
+    #[fail(display = "{}", _0)]
+    Timeout(String),
+    #[fail(display = "{}", _0)]
+    Internal(String),
+}
+
+impl ApiError {
+    /// A stable, machine-readable identifier of the kind of error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::LanguageNotFound(_) => "language_not_found",
+            ApiError::InvalidInput(_) => "invalid_input",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::TooManyRequests(..) => "too_many_requests",
+            ApiError::BackendUnavailable(_) => "backend_unavailable",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ApiError::LanguageNotFound(message)
+            | ApiError::InvalidInput(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::TooManyRequests(message, _)
+            | ApiError::BackendUnavailable(message)
+            | ApiError::Timeout(message)
+            | ApiError::Internal(message) => message,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::LanguageNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::TooManyRequests(..) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::BackendUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The JSON body of an error response
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ErrorResponse {
+    pub code: String,
     pub message: String,
 }
 
+impl From<&ApiError> for ErrorResponse {
+    fn from(item: &ApiError) -> Self {
+        ErrorResponse {
+            code: item.code().to_owned(),
+            message: item.message().to_owned(),
+        }
+    }
+}
+
 impl From<io::Error> for ApiError {
     fn from(item: io::Error) -> Self {
-        ApiError {
-            message: item.to_string(),
-        }
+        ApiError::Internal(item.to_string())
     }
 }
 
 impl From<std::string::FromUtf8Error> for ApiError {
     fn from(item: std::string::FromUtf8Error) -> Self {
-        ApiError {
-            message: item.to_string(),
-        }
+        ApiError::Internal(item.to_string())
     }
 }
 
 impl ResponseError for ApiError {
-    fn render_response(&self) -> HttpResponse {
-        error!("{}", self.message);
-        return HttpResponse::InternalServerError()
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status();
+
+        if status.is_server_error() {
+            error!("{}", self.message());
+        } else {
+            info!("{}", self.message());
+        }
+
+        let mut response = HttpResponse::build(status);
+
+        if let ApiError::TooManyRequests(_, Some(wait)) = self {
+            // Whole seconds, rounded up so that the retry isn't too early
+            let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response.header(RETRY_AFTER, seconds.to_string());
+        }
+
+        response
             .content_type("application/json")
-            .json(json!({ "message": self.message }));
+            .json(ErrorResponse::from(self))
+    }
+
+    fn render_response(&self) -> HttpResponse {
+        self.error_response()
     }
 }
@@ -1,8 +1,13 @@
-use actix_web::{web, HttpRequest, HttpResponse};
+use std::sync::Arc;
+
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
 use futures::future::Future;
 use juniper::http::graphiql::graphiql_source;
 use juniper::http::GraphQLRequest;
 
+use crate::graphql::schema::Context;
+use crate::language::handlers::request_ui_languages;
+use crate::server::auth::Client;
 use crate::server::state::State;
 
 pub fn graphiql(req: HttpRequest) -> HttpResponse {
@@ -13,17 +18,32 @@ pub fn graphiql(req: HttpRequest) -> HttpResponse {
 }
 
 pub fn graphql(
+    req: HttpRequest,
     state: web::Data<State>,
     request: web::Json<GraphQLRequest>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    web::block(move || {
-        let res = request.execute(&state.graphql_schema, &state);
-        Ok::<_, serde_json::error::Error>(serde_json::to_string(&res)?)
-    })
-    .map_err(actix_web::Error::from)
-    .and_then(|query| {
-        Ok(HttpResponse::Ok()
-            .content_type("application/json")
-            .body(query))
+    let client = req.extensions().get::<Arc<Client>>().cloned();
+    let client_ip = state
+        .rate_limiter
+        .request_client_ip(req.head().peer_addr, req.headers());
+    let context = Context::new(
+        state.get_ref().clone(),
+        client,
+        client_ip,
+        request_ui_languages(&req),
+    );
+
+    // The first execution only collects the lookups to resolve
+    request.execute(&state.graphql_schema, &context);
+
+    context.resolve().from_err().and_then(move |context| {
+        let res = request.execute(&context.state.graphql_schema, &context);
+        let body = serde_json::to_string(&res)?;
+
+        Ok::<_, actix_web::Error>(
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .body(body),
+        )
     })
 }
@@ -1,13 +1,179 @@
-use futures::future::Future;
-use juniper::{graphql_object, EmptyMutation, FieldResult, GraphQLObject, RootNode};
+use std::net::IpAddr;
+use std::sync::Arc;
 
-use crate::language::grammar::{self, GramcheckRequest};
-use crate::language::hyphenation::{self, HyphenationRequest};
-use crate::language::speller::{self, SpellerRequest};
-use crate::server::state::InnerState;
+use futures::future::{join_all, ok, Either, Future};
+use juniper::{
+    graphql_object, graphql_value, EmptyMutation, FieldError, FieldResult, GraphQLEnum,
+    GraphQLInputObject, GraphQLObject, RootNode,
+};
+
+use parking_lot::Mutex;
+
+use crate::error::ApiError;
+use crate::language::batch::{self, check_batch, BatchResponse, BatchService};
+use crate::language::grammar::{self, GramcheckRequest, GramcheckResponse};
+use crate::language::hyphenation::{self, HyphenationRequest, HyphenationResponse};
+use crate::language::preferences;
+use crate::language::speller::{self, SpellerRequest, SpellerResponse};
+use crate::server::auth::Client;
+use crate::server::state::State;
 use divvunspell::speller::suggestion::Suggestion;
 
-impl juniper::Context for InnerState {}
+/// A call to a language service needed by a query
+#[derive(Debug, Clone, PartialEq)]
+enum Lookup {
+    Speller(String, SpellerRequest),
+    Grammar(String, GramcheckRequest),
+    Hyphenation(String, HyphenationRequest),
+    Batch(Vec<batch::BatchItem>),
+}
+
+#[derive(Clone)]
+enum Answer {
+    Speller(SpellerResponse),
+    Grammar(GramcheckResponse),
+    Hyphenation(HyphenationResponse),
+    Batch(BatchResponse),
+}
+
+/// Resolvers can't wait for the language services without blocking, so a query is executed
+/// twice: the first time only collects the lookups it needs, which are then resolved at the same
+/// time, and the second time answers them from the results. While collecting, fields answer
+/// with placeholders, since an error in a field would leave the fields after it unresolved.
+pub struct Context {
+    pub state: State,
+    /// The client making the request, if authentication is enabled
+    pub client: Option<Arc<Client>>,
+    /// The address of the client, for rate limiting the items of a batch
+    pub client_ip: Option<IpAddr>,
+    /// The UI languages of the request's `Accept-Language` header, most preferred first
+    pub ui_languages: Vec<String>,
+    lookups: Mutex<Vec<(Lookup, Option<Result<Answer, ApiError>>)>>,
+    /// Set once the lookups are resolved, after which no more are collected
+    resolved: bool,
+}
+
+impl juniper::Context for Context {}
+
+impl Context {
+    pub fn new(
+        state: State,
+        client: Option<Arc<Client>>,
+        client_ip: Option<IpAddr>,
+        ui_languages: Vec<String>,
+    ) -> Self {
+        Context {
+            state,
+            client,
+            client_ip,
+            ui_languages,
+            lookups: Mutex::new(vec![]),
+            resolved: false,
+        }
+    }
+
+    fn authorize(&self, service: &str, language: &str) -> FieldResult<()> {
+        match &self.client {
+            Some(client) => client.authorize(service, language).map_err(field_error),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a lookup against the client's limits like a request of its own
+    fn count(&self, lookup: &Lookup) -> Result<(), ApiError> {
+        match (&self.client, lookup) {
+            // The items of a batch are counted as they are checked
+            (None, _) | (_, Lookup::Batch(_)) => Ok(()),
+            (Some(client), _) => self.state.authenticator.count(client),
+        }
+    }
+
+    /// The answer to the lookup once the lookups are resolved. Until then the lookup is collected
+    /// and `None` returned, for the field to answer with a placeholder.
+    fn lookup(&self, lookup: Lookup) -> FieldResult<Option<Answer>> {
+        let mut lookups = self.lookups.lock();
+
+        if let Some((_, answer)) = lookups.iter().find(|(known, _)| *known == lookup) {
+            return match answer {
+                Some(result) => result.clone().map(Some).map_err(field_error),
+                None => Ok(None),
+            };
+        }
+
+        if self.resolved {
+            return Err(field_error(ApiError::Internal(
+                "The lookup wasn't collected before resolving".to_owned(),
+            )));
+        }
+
+        // A lookup over the client's limits is answered with its error right away
+        match self.count(&lookup) {
+            Ok(()) => {
+                lookups.push((lookup, None));
+                Ok(None)
+            }
+            Err(e) => {
+                lookups.push((lookup, Some(Err(e.clone()))));
+                Err(field_error(e))
+            }
+        }
+    }
+
+    fn start(&self, lookup: &Lookup) -> Box<dyn Future<Item = Answer, Error = ApiError>> {
+        let language_functions = &self.state.language_functions;
+
+        match lookup.clone() {
+            Lookup::Speller(language, request) => Box::new(
+                language_functions
+                    .spelling_suggestions
+                    .suggestions(request, &language)
+                    .map(Answer::Speller),
+            ),
+            Lookup::Grammar(language, request) => Box::new(
+                language_functions
+                    .grammar_suggestions
+                    .suggestions(request, &language)
+                    .map(Answer::Grammar),
+            ),
+            Lookup::Hyphenation(language, request) => Box::new(
+                language_functions
+                    .hyphenation_suggestions
+                    .suggestions(request, &language)
+                    .map(Answer::Hyphenation),
+            ),
+            Lookup::Batch(items) => Box::new(
+                check_batch(
+                    self.state.clone(),
+                    self.client.clone(),
+                    self.client_ip,
+                    items,
+                )
+                .map(Answer::Batch),
+            ),
+        }
+    }
+
+    /// Resolves the collected lookups concurrently. A failing lookup only fails its own field.
+    pub fn resolve(mut self) -> impl Future<Item = Context, Error = ApiError> {
+        let lookups: Vec<_> = self
+            .lookups
+            .lock()
+            .iter()
+            .map(|(lookup, answer)| match answer {
+                Some(result) => Either::A(ok(result.clone())),
+                None => Either::B(self.start(lookup).then(ok::<_, ApiError>)),
+            })
+            .collect();
+        self.resolved = true;
+
+        join_all(lookups).map(move |results| {
+            for ((_, answer), result) in self.lookups.lock().iter_mut().zip(results) {
+                *answer = Some(result);
+            }
+            self
+        })
+    }
+}
 
 #[derive(Debug)]
 pub struct Suggestions {
@@ -25,11 +191,59 @@ pub struct Speller {
     pub results: Vec<SpellerResult>,
 }
 
+impl From<speller::SpellerResponse> for Speller {
+    fn from(item: speller::SpellerResponse) -> Self {
+        Speller {
+            results: item
+                .results
+                .into_iter()
+                .map(|suggestion| SpellerResult::from(suggestion))
+                .collect(),
+        }
+    }
+}
+
+impl From<grammar::GramcheckResponse> for Grammar {
+    fn from(item: grammar::GramcheckResponse) -> Self {
+        Grammar {
+            errs: item
+                .errs
+                .into_iter()
+                .map(|response| GramcheckErrResponse::from(response))
+                .collect(),
+        }
+    }
+}
+
+impl From<hyphenation::HyphenationResponse> for Hyphenation {
+    fn from(item: hyphenation::HyphenationResponse) -> Self {
+        Hyphenation {
+            results: item
+                .results
+                .into_iter()
+                .map(|result| HyphenationResult::from(result))
+                .collect(),
+        }
+    }
+}
+
 #[derive(GraphQLObject)]
 pub struct SpellerResult {
     pub word: String,
     pub is_correct: bool,
     pub suggestions: Vec<SpellerSuggestion>,
+    #[graphql(description = "Start of the word within the text in UTF-8 bytes")]
+    pub start_byte: i32,
+    #[graphql(description = "End of the word within the text in UTF-8 bytes")]
+    pub end_byte: i32,
+    #[graphql(description = "Start of the word within the text in UTF-16 code units")]
+    pub start_utf16: i32,
+    #[graphql(description = "End of the word within the text in UTF-16 code units")]
+    pub end_utf16: i32,
+    #[graphql(description = "Start of the word within the text in Unicode code points")]
+    pub start_char: i32,
+    #[graphql(description = "End of the word within the text in Unicode code points")]
+    pub end_char: i32,
 }
 
 impl From<speller::SpellerResult> for SpellerResult {
@@ -42,6 +256,12 @@ impl From<speller::SpellerResult> for SpellerResult {
                 .into_iter()
                 .map(|suggestion| SpellerSuggestion::from(suggestion))
                 .collect(),
+            start_byte: item.start_byte as i32,
+            end_byte: item.end_byte as i32,
+            start_utf16: item.start_utf16 as i32,
+            end_utf16: item.end_utf16 as i32,
+            start_char: item.start_char as i32,
+            end_char: item.end_char as i32,
         }
     }
 }
@@ -126,108 +346,402 @@ impl From<hyphenation::HyphenationPattern> for HyphenationPattern {
     }
 }
 
+#[derive(GraphQLObject)]
+pub struct Description {
+    pub ui_language: String,
+    pub text: String,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "A category of grammar errors that can be turned on or off")]
+pub struct ErrorCategory {
+    #[graphql(description = "The error tag, or the pattern matching the tags of a regex category")]
+    pub id: Option<String>,
+    pub regex: bool,
+    pub enabled_by_default: bool,
+    pub descriptions: Vec<Description>,
+}
+
+impl From<preferences::ErrorCategory> for ErrorCategory {
+    fn from(item: preferences::ErrorCategory) -> Self {
+        ErrorCategory {
+            id: item.id,
+            regex: item.regex,
+            enabled_by_default: item.enabled_by_default,
+            descriptions: item
+                .descriptions
+                .into_iter()
+                .map(|(ui_language, text)| Description { ui_language, text })
+                .collect(),
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct GrammarPreferences {
+    #[graphql(description = "Pipelines that can be chosen for grammar checking")]
+    pub pipelines: Vec<String>,
+    pub categories: Vec<ErrorCategory>,
+}
+
+impl From<preferences::GrammarPreferences> for GrammarPreferences {
+    fn from(item: preferences::GrammarPreferences) -> Self {
+        GrammarPreferences {
+            pipelines: item.pipelines,
+            categories: item
+                .categories
+                .into_iter()
+                .map(ErrorCategory::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(GraphQLEnum, Clone, Copy)]
+pub enum Service {
+    Speller,
+    Grammar,
+    Hyphenation,
+}
+
+impl From<Service> for BatchService {
+    fn from(item: Service) -> Self {
+        match item {
+            Service::Speller => BatchService::Speller,
+            Service::Grammar => BatchService::Grammar,
+            Service::Hyphenation => BatchService::Hyphenation,
+        }
+    }
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "A text to check as part of a batch")]
+pub struct BatchItem {
+    #[graphql(description = "Identifies the item's result, chosen by the client")]
+    pub id: String,
+    pub language: String,
+    pub services: Vec<Service>,
+    pub text: String,
+}
+
+impl From<BatchItem> for batch::BatchItem {
+    fn from(item: BatchItem) -> Self {
+        batch::BatchItem {
+            id: item.id,
+            language: item.language,
+            services: item.services.into_iter().map(BatchService::from).collect(),
+            text: item.text,
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct BatchError {
+    pub service: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl From<batch::BatchError> for BatchError {
+    fn from(item: batch::BatchError) -> Self {
+        BatchError {
+            service: item.service,
+            code: item.code,
+            message: item.message,
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct BatchItemResult {
+    pub id: String,
+    pub speller: Option<Speller>,
+    pub grammar: Option<Grammar>,
+    pub hyphenation: Option<Hyphenation>,
+    #[graphql(description = "Errors of the services that failed for this item")]
+    pub errors: Vec<BatchError>,
+}
+
+impl From<batch::BatchItemResult> for BatchItemResult {
+    fn from(item: batch::BatchItemResult) -> Self {
+        BatchItemResult {
+            id: item.id,
+            speller: item.speller.map(Speller::from),
+            grammar: item.grammar.map(Grammar::from),
+            hyphenation: item.hyphenation.map(Hyphenation::from),
+            errors: item.errors.into_iter().map(BatchError::from).collect(),
+        }
+    }
+}
+
+/// Converts the error into a GraphQL error carrying the error code as an extension
+fn field_error(error: ApiError) -> FieldError {
+    let code = error.code();
+    FieldError::new(error.message(), graphql_value!({ "code": code }))
+}
+
+fn mismatched_answer() -> FieldError {
+    field_error(ApiError::Internal(
+        "The answer doesn't match the lookup".to_owned(),
+    ))
+}
+
 pub struct QueryRoot;
 
-graphql_object!(QueryRoot: InnerState |&self| {
+graphql_object!(QueryRoot: Context |&self| {
     field suggestions(&executor, text: String, language: String) -> FieldResult<Suggestions> {
         Ok(Suggestions { text, language })
     }
+
+    field grammar_preferences(&executor, language: String) -> FieldResult<GrammarPreferences> {
+        let context = executor.context();
+        context.authorize("grammar", &language)?;
+
+        match context.state.gramcheck_preferences.read().get(&language) {
+            Some(Ok(preferences)) => Ok(GrammarPreferences::from(preferences.clone())),
+            Some(Err(e)) => Err(field_error(ApiError::BackendUnavailable(format!(
+                "Failed to list the grammar checker preferences for language {}: {}",
+                language, e
+            )))),
+            None => Err(field_error(ApiError::LanguageNotFound(format!(
+                "No grammar checker available for language {}",
+                language
+            )))),
+        }
+    }
+
+    field batch(&executor, items: Vec<BatchItem>) -> FieldResult<Vec<BatchItemResult>> {
+        let items = items.into_iter().map(batch::BatchItem::from).collect();
+
+        match executor.context().lookup(Lookup::Batch(items))? {
+            Some(Answer::Batch(response)) => Ok(response
+                .results
+                .into_iter()
+                .map(BatchItemResult::from)
+                .collect()),
+            None => Ok(vec![]),
+            Some(_) => Err(mismatched_answer()),
+        }
+    }
 });
 
-graphql_object!(Suggestions: InnerState |&self| {
+graphql_object!(Suggestions: Context |&self| {
     description: "Text suggestions"
 
-    field grammar(&executor) -> FieldResult<Grammar> {
-        get_grammar_suggestions(executor.context(), &self.text, &self.language)
+    field grammar(
+        &executor,
+        ignore_tags: Option<Vec<String>> as "Error tags that should not be reported",
+        only_tags: Option<Vec<String>> as "If set, only errors with these tags are reported",
+        pipeline: Option<String> as "The divvun-checker pipeline to run, the language's default if not set",
+        ui_language: Option<String> as "The UI language of the error titles and descriptions, taken from the Accept-Language header if not set"
+    ) -> FieldResult<Grammar> {
+        let mut request = GramcheckRequest {
+            text: self.text.clone(),
+            ignore_tags: ignore_tags.unwrap_or_default(),
+            only_tags,
+            pipeline,
+            ui_language,
+            tag_filter: None,
+        };
+
+        let context = executor.context();
+        context.authorize("grammar", &self.language)?;
+        context.state.config.limits.check_text("grammar", &self.text).map_err(field_error)?;
+
+        {
+            let preferences = context.state.gramcheck_preferences.read();
+            request.negotiate_ui_language(&self.language, &preferences, &context.ui_languages);
+            request.validate(&self.language, &preferences).map_err(field_error)?;
+            request.compile_tags(&self.language, &preferences);
+        }
+
+        match context.lookup(Lookup::Grammar(self.language.clone(), request))? {
+            Some(Answer::Grammar(response)) => Ok(Grammar::from(response)),
+            None => Ok(Grammar { errs: vec![] }),
+            Some(_) => Err(mismatched_answer()),
+        }
     }
 
-    field speller(&executor) -> FieldResult<Speller> {
-        get_speller_suggestions(executor.context(), &self.text, &self.language)
+    field speller(
+        &executor,
+        max_suggestions: Option<i32> as "Maximum number of suggestions per word",
+        max_weight: Option<f64> as "Maximum weight of a suggestion",
+        beam: Option<f64> as "Maximum weight difference from the best suggestion",
+        recase: Option<bool> as "Whether differently cased forms of the word are considered",
+        suggest_correct: Option<bool> as "Whether suggestions are generated for correct words",
+        only_misspelled: Option<bool> as "Whether only misspelled words are returned"
+    ) -> FieldResult<Speller> {
+        let request = SpellerRequest {
+            text: self.text.clone(),
+            max_suggestions: max_suggestions.map(|n| n.max(0) as usize),
+            max_weight: max_weight.map(|w| w as f32),
+            beam: beam.map(|b| b as f32),
+            recase,
+            suggest_correct,
+            only_misspelled: only_misspelled.unwrap_or(false),
+        };
+
+        let context = executor.context();
+        context.authorize("speller", &self.language)?;
+        context.state.config.limits.check_text("speller", &self.text).map_err(field_error)?;
+
+        match context.lookup(Lookup::Speller(self.language.clone(), request))? {
+            Some(Answer::Speller(response)) => Ok(Speller::from(response)),
+            None => Ok(Speller { results: vec![] }),
+            Some(_) => Err(mismatched_answer()),
+        }
     }
 
     field hyphenation(&executor) -> FieldResult<Hyphenation> {
-        get_hyphenation_suggestions(executor.context(), &self.text, &self.language)
+        let context = executor.context();
+        context.authorize("hyphenation", &self.language)?;
+        context.state.config.limits.check_text("hyphenation", &self.text).map_err(field_error)?;
+
+        let request = HyphenationRequest {
+            text: self.text.clone(),
+        };
+
+        match context.lookup(Lookup::Hyphenation(self.language.clone(), request))? {
+            Some(Answer::Hyphenation(response)) => Ok(Hyphenation::from(response)),
+            None => Ok(Hyphenation { results: vec![] }),
+            Some(_) => Err(mismatched_answer()),
+        }
     }
 });
 
-fn get_grammar_suggestions(state: &InnerState, text: &str, language: &str) -> FieldResult<Grammar> {
-    let grammar_suggestions = state
-        .language_functions
-        .grammar_suggestions
-        .suggestions(
-            GramcheckRequest {
-                text: text.to_owned(),
-            },
-            language,
-        )
-        .wait();
+pub type Schema = RootNode<'static, QueryRoot, EmptyMutation<Context>>;
 
-    match grammar_suggestions {
-        Ok(gram_output) => Ok(Grammar {
-            errs: gram_output
-                .errs
-                .into_iter()
-                .map(|response| GramcheckErrResponse::from(response))
-                .collect(),
-        }),
-        Err(error) => Err(error)?,
-    }
+pub fn create_schema() -> Schema {
+    Schema::new(QueryRoot {}, EmptyMutation::new())
 }
 
-fn get_speller_suggestions(state: &InnerState, text: &str, language: &str) -> FieldResult<Speller> {
-    let speller_suggestions = state
-        .language_functions
-        .spelling_suggestions
-        .suggestions(
-            SpellerRequest {
-                text: text.to_owned(),
-            },
-            language,
-        )
-        .wait();
+#[cfg(test)]
+mod test {
+    use std::marker::PhantomData;
 
-    match speller_suggestions {
-        Ok(speller_output) => Ok(Speller {
-            results: speller_output
-                .results
-                .into_iter()
-                .map(|suggestion| SpellerResult::from(suggestion))
-                .collect(),
-        }),
-        Err(error) => Err(error)?,
-    }
-}
-
-fn get_hyphenation_suggestions(
-    state: &InnerState,
-    text: &str,
-    language: &str,
-) -> FieldResult<Hyphenation> {
-    let hyphenation_suggestions = state
-        .language_functions
-        .hyphenation_suggestions
-        .suggestions(
-            HyphenationRequest {
-                text: text.to_owned(),
+    use juniper::http::GraphQLRequest;
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::TomlConfig;
+    use crate::language::grammar::GramcheckErrResponse;
+    use crate::language::hyphenation::HyphenationResult;
+    use crate::language::speller::SpellerResult;
+    use crate::server::state::test_support::{test_state, Fixed};
+    use crate::server::state::LanguageFunctions;
+
+    fn state() -> State {
+        let text = "pákhat".to_owned();
+
+        let speller = SpellerResponse {
+            text: text.clone(),
+            results: vec![SpellerResult {
+                word: text.clone(),
+                is_correct: false,
+                suggestions: vec![],
+                start_byte: 0,
+                end_byte: 7,
+                start_utf16: 0,
+                end_utf16: 6,
+                start_char: 0,
+                end_char: 6,
+            }],
+        };
+        let grammar = GramcheckResponse {
+            text: text.clone(),
+            errs: vec![GramcheckErrResponse {
+                error_text: text.clone(),
+                start_index: 0,
+                end_index: 6,
+                error_code: "typo".to_owned(),
+                description: "Ii leat sátnelisttus".to_owned(),
+                suggestions: vec![],
+                title: "Čállinmeattáhusat".to_owned(),
+            }],
+        };
+        let hyphenation = HyphenationResponse {
+            text: text.clone(),
+            results: vec![HyphenationResult {
+                word: text,
+                hyphenations: vec![],
+            }],
+        };
+
+        test_state(
+            TomlConfig::default(),
+            LanguageFunctions {
+                spelling_suggestions: Box::new(Fixed(speller, PhantomData)),
+                grammar_suggestions: Box::new(Fixed(grammar, PhantomData)),
+                hyphenation_suggestions: Box::new(Fixed(hyphenation, PhantomData)),
             },
-            language,
         )
-        .wait();
+    }
 
-    match hyphenation_suggestions {
-        Ok(hyphenation_response) => Ok(Hyphenation {
-            results: hyphenation_response
-                .results
-                .into_iter()
-                .map(|result| HyphenationResult::from(result))
-                .collect(),
-        }),
-        Err(error) => Err(error)?,
+    /// Runs the query like the handler does
+    fn execute(state: &State, query: &str) -> serde_json::Value {
+        let request = GraphQLRequest::new(query.to_owned(), None, None);
+        let context = Context::new(Arc::clone(state), None, None, vec![]);
+
+        request.execute(&state.graphql_schema, &context);
+        let context = context.resolve().wait().unwrap();
+
+        serde_json::to_value(request.execute(&state.graphql_schema, &context)).unwrap()
     }
-}
 
-pub type Schema = RootNode<'static, QueryRoot, EmptyMutation<InnerState>>;
+    #[test]
+    fn test_several_fields() {
+        let response = execute(
+            &state(),
+            r#"{ suggestions(text: "pákhat", language: "se") {
+                speller { results { word } }
+                grammar { errs { errorCode } }
+                hyphenation { results { word } }
+                onlyMisspelled: speller(onlyMisspelled: true) { results { word } }
+            } }"#,
+        );
 
-pub fn create_schema() -> Schema {
-    Schema::new(QueryRoot {}, EmptyMutation::new())
+        assert_eq!(response.get("errors"), None);
+        assert_eq!(
+            response["data"]["suggestions"],
+            json!({
+                "speller": { "results": [{ "word": "pákhat" }] },
+                "grammar": { "errs": [{ "errorCode": "typo" }] },
+                "hyphenation": { "results": [{ "word": "pákhat" }] },
+                "onlyMisspelled": { "results": [{ "word": "pákhat" }] },
+            })
+        );
+    }
+
+    #[test]
+    fn test_failing_field() {
+        let mut state = state();
+        Arc::get_mut(&mut state)
+            .unwrap()
+            .config
+            .limits
+            .speller_max_chars = 3;
+
+        // A field failing before its lookup fails the whole non-null suggestions object, the
+        // fields after it included, in both executions alike
+        let response = execute(
+            &state,
+            r#"{ suggestions(text: "pákhat", language: "se") {
+                speller { results { word } }
+                hyphenation { results { word } }
+            } }"#,
+        );
+        assert_eq!(response["data"], json!(null));
+        assert_eq!(
+            response["errors"][0]["extensions"]["code"],
+            "payload_too_large"
+        );
+
+        let response = execute(
+            &state,
+            r#"{ suggestions(text: "pákhat", language: "se") {
+                hyphenation { results { word } }
+            } }"#,
+        );
+        assert_eq!(response.get("errors"), None);
+    }
 }
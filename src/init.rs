@@ -1,13 +1,15 @@
+use std::io::{self, ErrorKind};
 use std::{env, fs};
 
-use actix::{Addr, SystemRunner};
+use actix::{Addr, System, SystemRunner};
 use actix_web::dev::Server;
-use clap::{crate_version, App as ClapApp, Arg, ArgMatches};
-use log::info;
+use clap::{crate_version, App as ClapApp, Arg, ArgMatches, SubCommand};
+use log::{info, warn};
 
-use crate::config::{Config, TomlConfig};
+use crate::config::{Config, ConfigError, TomlConfig};
+use crate::lsp::{self, Transport};
 use crate::server::start_server;
-use crate::server::state::create_state;
+use crate::server::state::{create_state, State};
 use crate::watcher::{Start, Watcher};
 
 pub struct App {
@@ -16,8 +18,8 @@ pub struct App {
     pub watcher: Addr<Watcher>,
 }
 
-pub fn init_config() -> TomlConfig {
-    let matches = ClapApp::new("divvun-api")
+pub fn init_cli() -> ArgMatches<'static> {
+    ClapApp::new("divvun-api")
         .version(crate_version!())
         .arg(
             Arg::with_name("config")
@@ -25,39 +27,66 @@ pub fn init_config() -> TomlConfig {
                 .long("config")
                 .value_name("FILE")
                 .help("Set a custom TOML config file")
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("lsp")
+                .about("Run as a language server over stdio instead of the HTTP server")
+                .arg(
+                    Arg::with_name("tcp")
+                        .long("tcp")
+                        .value_name("ADDR")
+                        .help("Listen for language server clients on ADDR instead of stdio")
+                        .takes_value(true),
+                ),
+        )
+        .get_matches()
+}
 
-    get_config(&matches)
+/// Reads the config file and applies the `DIVVUN_API_*` environment variable overrides
+pub fn init_config(matches: &ArgMatches<'_>) -> Result<TomlConfig, ConfigError> {
+    get_config(matches)
 }
 
-pub fn init_system(config: &Config) -> (App, SystemRunner) {
+pub fn init_system(config: &Config) -> io::Result<(App, SystemRunner)> {
     let system = actix::System::new("divvun-api");
     let state = create_state(&config);
 
     let server_state = state.clone();
-    let server = start_server(server_state, &config);
+    let server = start_server(server_state, &config)?;
 
-    let watcher_state = state.clone();
+    let addr = start_watcher(state);
 
-    let addr = actix::SyncArbiter::start(1, move || Watcher);
-    addr.try_send(Start {
-        state: watcher_state,
-    })
-    .unwrap();
-
-    (
+    Ok((
         App {
             config: config.clone(),
             server,
             watcher: addr,
         },
         system,
-    )
+    ))
+}
+
+/// Starts the language server in place of the HTTP server
+pub fn init_lsp(config: &Config, transport: Transport) -> io::Result<SystemRunner> {
+    let system = actix::System::new("divvun-api");
+    let state = create_state(&config);
+
+    lsp::start(state.clone(), transport, System::current())?;
+    start_watcher(state);
+
+    Ok(system)
+}
+
+fn start_watcher(state: State) -> Addr<Watcher> {
+    let addr = actix::SyncArbiter::start(1, move || Watcher);
+    addr.try_send(Start { state }).unwrap();
+
+    addr
 }
 
-fn get_config(matches: &ArgMatches<'_>) -> TomlConfig {
+fn get_config(matches: &ArgMatches<'_>) -> Result<TomlConfig, ConfigError> {
     let default_path = "config.toml";
     let divvun_env_var = "DIVVUN_API_CONFIG_PATH";
 
@@ -79,10 +108,19 @@ fn get_config(matches: &ArgMatches<'_>) -> TomlConfig {
         .to_owned(),
     };
 
-    let config =
-        fs::read_to_string(&config_file).expect(&format!("Failed to open {}", config_file));
-    let config: TomlConfig =
-        toml::from_str(&config).expect(&format!("Failed to convert {} to TOML", config_file));
+    let mut config = match fs::read_to_string(&config_file) {
+        Ok(config) => {
+            toml::from_str(&config).map_err(|e| ConfigError::Parse(config_file.clone(), e))?
+        }
+        // Without a config file everything has to come from the environment
+        Err(ref e) if e.kind() == ErrorKind::NotFound && config_file == default_path => {
+            warn!("{} not found, using defaults", default_path);
+            TomlConfig::default()
+        }
+        Err(e) => return Err(ConfigError::Read(config_file, e)),
+    };
+
+    config.apply_env()?;
 
-    config
+    Ok(config)
 }
@@ -0,0 +1,342 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::future::{err, ok, Either, Future};
+use futures::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+
+use crate::error::ApiError;
+use crate::server::auth::Client;
+use crate::server::state::State;
+
+use super::grammar::{GramcheckRequest, GramcheckResponse};
+use super::hyphenation::{HyphenationRequest, HyphenationResponse};
+use super::speller::{SpellerRequest, SpellerResponse};
+
+/// Number of items of a batch that are checked at the same time, so that a large batch
+/// doesn't fill the executors' mailboxes and time out requests queued behind it
+const MAX_CONCURRENT_ITEMS: usize = 16;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchService {
+    Speller,
+    Grammar,
+    Hyphenation,
+}
+
+impl BatchService {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BatchService::Speller => "speller",
+            BatchService::Grammar => "grammar",
+            BatchService::Hyphenation => "hyphenation",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct BatchItem {
+    /// Identifies the item's result, chosen by the client
+    pub id: String,
+    pub language: String,
+    pub services: Vec<BatchService>,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchError {
+    pub service: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl BatchError {
+    fn new(service: BatchService, error: &ApiError) -> Self {
+        BatchError {
+            service: service.as_str().to_owned(),
+            code: error.code().to_owned(),
+            message: error.message().to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BatchItemResult {
+    pub id: String,
+    pub speller: Option<SpellerResponse>,
+    pub grammar: Option<GramcheckResponse>,
+    pub hyphenation: Option<HyphenationResponse>,
+    /// Errors of the services that failed, the other services' results are still returned
+    pub errors: Vec<BatchError>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BatchResponse {
+    pub results: Vec<BatchItemResult>,
+}
+
+type ServiceResult<T> = Option<Result<T, ApiError>>;
+
+/// Runs the service for the item if it was requested and the client may use it
+fn check_service<T, F>(
+    state: &State,
+    client: &Option<Arc<Client>>,
+    item: &BatchItem,
+    service: BatchService,
+    check: F,
+) -> Box<dyn Future<Item = ServiceResult<T>, Error = ApiError>>
+where
+    T: 'static,
+    F: FnOnce() -> Box<dyn Future<Item = T, Error = ApiError>>,
+{
+    if !item.services.contains(&service) {
+        return Box::new(ok::<_, ApiError>(None));
+    }
+
+    // Every service an item is checked with counts as a request of its own
+    let allowed = state
+        .config
+        .limits
+        .check_text(service.as_str(), &item.text)
+        .and_then(|_| match client {
+            Some(client) => client
+                .authorize(service.as_str(), &item.language)
+                .and_then(|_| state.authenticator.count(client)),
+            None => Ok(()),
+        });
+
+    match allowed {
+        Ok(()) => Box::new(check().then(|result| ok::<_, ApiError>(Some(result)))),
+        Err(e) => Box::new(ok::<_, ApiError>(Some(Err(e)))),
+    }
+}
+
+pub fn check_item(
+    state: &State,
+    client: &Option<Arc<Client>>,
+    item: BatchItem,
+) -> impl Future<Item = BatchItemResult, Error = ApiError> {
+    let language_functions = &state.language_functions;
+
+    let speller = check_service(state, client, &item, BatchService::Speller, || {
+        language_functions.spelling_suggestions.suggestions(
+            SpellerRequest {
+                text: item.text.clone(),
+                ..Default::default()
+            },
+            &item.language,
+        )
+    });
+
+    let grammar = check_service(state, client, &item, BatchService::Grammar, || {
+        language_functions.grammar_suggestions.suggestions(
+            GramcheckRequest {
+                text: item.text.clone(),
+                ignore_tags: vec![],
+                only_tags: None,
+                pipeline: None,
+                ui_language: None,
+                tag_filter: None,
+            },
+            &item.language,
+        )
+    });
+
+    let hyphenation = check_service(state, client, &item, BatchService::Hyphenation, || {
+        language_functions.hyphenation_suggestions.suggestions(
+            HyphenationRequest {
+                text: item.text.clone(),
+            },
+            &item.language,
+        )
+    });
+
+    let id = item.id;
+
+    speller
+        .join3(grammar, hyphenation)
+        .map(move |(speller, grammar, hyphenation)| {
+            let mut errors = vec![];
+
+            let speller = match speller {
+                Some(Err(e)) => {
+                    errors.push(BatchError::new(BatchService::Speller, &e));
+                    None
+                }
+                other => other.and_then(Result::ok),
+            };
+            let grammar = match grammar {
+                Some(Err(e)) => {
+                    errors.push(BatchError::new(BatchService::Grammar, &e));
+                    None
+                }
+                other => other.and_then(Result::ok),
+            };
+            let hyphenation = match hyphenation {
+                Some(Err(e)) => {
+                    errors.push(BatchError::new(BatchService::Hyphenation, &e));
+                    None
+                }
+                other => other.and_then(Result::ok),
+            };
+
+            BatchItemResult {
+                id,
+                speller,
+                grammar,
+                hyphenation,
+                errors,
+            }
+        })
+}
+
+/// The result of an item that wasn't checked, failing every service it requested
+fn rejected_item(item: BatchItem, error: &ApiError) -> BatchItemResult {
+    BatchItemResult {
+        errors: item
+            .services
+            .iter()
+            .map(|service| BatchError::new(*service, error))
+            .collect(),
+        id: item.id,
+        speller: None,
+        grammar: None,
+        hyphenation: None,
+    }
+}
+
+/// Checks every item of the batch, returning the results in the order of the items. A failing
+/// item doesn't fail the batch, its errors are returned with its result. Every item counts as a
+/// request against the rate limit of the client's IP address, the first one having been counted
+/// as the batch's request.
+pub fn check_batch(
+    state: State,
+    client: Option<Arc<Client>>,
+    client_ip: Option<IpAddr>,
+    items: Vec<BatchItem>,
+) -> Box<dyn Future<Item = BatchResponse, Error = ApiError>> {
+    let max_items = state.config.limits.batch_max_items;
+    if items.len() > max_items {
+        return Box::new(err::<BatchResponse, _>(ApiError::PayloadTooLarge(format!(
+            "A batch can contain at most {} items",
+            max_items
+        ))));
+    }
+
+    Box::new(
+        stream::iter_ok::<_, ApiError>(items.into_iter().enumerate())
+            .map(move |(index, item)| {
+                let limited = match client_ip {
+                    Some(client_ip) if index > 0 => {
+                        state.rate_limiter.check(client_ip, Instant::now())
+                    }
+                    _ => Ok(()),
+                };
+
+                match limited {
+                    Ok(()) => Either::A(check_item(&state, &client, item)),
+                    Err(e) => Either::B(ok(rejected_item(item, &e))),
+                }
+            })
+            .buffered(MAX_CONCURRENT_ITEMS)
+            .collect()
+            .map(|results| BatchResponse { results }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::marker::PhantomData;
+
+    use super::*;
+    use crate::config::{RateLimitSettings, TomlConfig};
+    use crate::server::state::test_support::{missing_functions, test_state, Fixed};
+    use crate::server::state::LanguageFunctions;
+
+    fn state(toml_config: TomlConfig) -> State {
+        let hyphenation = HyphenationResponse {
+            text: "pákhat".to_owned(),
+            results: vec![],
+        };
+
+        test_state(
+            toml_config,
+            LanguageFunctions {
+                hyphenation_suggestions: Box::new(Fixed(hyphenation, PhantomData)),
+                ..missing_functions()
+            },
+        )
+    }
+
+    fn items(count: usize) -> Vec<BatchItem> {
+        (0..count)
+            .map(|index| BatchItem {
+                id: index.to_string(),
+                language: "se".to_owned(),
+                services: vec![BatchService::Hyphenation],
+                text: "pákhat".to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rate_limited_items() {
+        let state = state(TomlConfig {
+            rate_limit: RateLimitSettings {
+                enabled: true,
+                burst: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let client_ip = "10.0.0.1".parse().unwrap();
+
+        // The first item is counted as the request itself, the second takes the only token left
+        let response = check_batch(state, None, Some(client_ip), items(3))
+            .wait()
+            .unwrap();
+        let results = response.results;
+
+        assert_eq!(results.len(), 3);
+        for result in &results[..2] {
+            assert!(result.hyphenation.is_some());
+            assert!(result.errors.is_empty());
+        }
+        assert!(results[2].hyphenation.is_none());
+        assert_eq!(results[2].errors.len(), 1);
+        assert_eq!(results[2].errors[0].service, "hyphenation");
+        assert_eq!(results[2].errors[0].code, "too_many_requests");
+    }
+
+    #[test]
+    fn test_unlimited_without_client_ip() {
+        let state = state(TomlConfig {
+            rate_limit: RateLimitSettings {
+                enabled: true,
+                burst: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let response = check_batch(state, None, None, items(3)).wait().unwrap();
+
+        assert!(response
+            .results
+            .iter()
+            .all(|result| result.hyphenation.is_some()));
+    }
+
+    #[test]
+    fn test_too_many_items() {
+        let state = state(TomlConfig::default());
+        let max_items = state.config.limits.batch_max_items;
+
+        match check_batch(state, None, None, items(max_items + 1)).wait() {
+            Err(ApiError::PayloadTooLarge(_)) => (),
+            _ => panic!("expected the batch to be too large"),
+        }
+    }
+}
@@ -28,7 +28,7 @@ pub struct AvailableLanguagesResponse {
 }
 
 impl DataFileType {
-    pub fn as_ext(&self) -> &str {
+    pub fn as_ext(&self) -> &'static str {
         match self {
             DataFileType::Grammar => "zcheck",
             DataFileType::Spelling => "zhfst",
@@ -36,7 +36,7 @@ impl DataFileType {
         }
     }
 
-    pub fn as_dir(&self) -> &str {
+    pub fn as_dir(&self) -> &'static str {
         match self {
             DataFileType::Grammar => "grammar",
             DataFileType::Spelling => "spelling",
@@ -1,62 +1,414 @@
-use std::collections::BTreeMap;
-use std::io::{BufRead, BufReader, Error, Write};
-use std::process::{Child, Command, Stdio};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 
 use actix::prelude::*;
-use futures::future::{err, ok, Future};
+use futures::future::{err, join_all, ok, Future};
 use hashbrown::HashMap;
 use log::{debug, error, info, warn};
-use parking_lot::RwLock;
-use regex::Regex;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 
+use divvunspell::tokenizer::Tokenize;
+
+use crate::config::{language_settings, Languages};
 use crate::error::ApiError;
+use crate::language::pool::ExecutorPool;
+use crate::language::preferences::{GramcheckPreferences, GrammarPreferences, TagFilter};
+use crate::metrics::{GRAMCHECKER_RESTARTS, WORDS_PROCESSED};
 use crate::server::state::{LanguageSuggestions, UnhoistFutureExt};
+use crate::server::status::{LanguageStatus, SharedWorkerStatus};
 
-pub struct GramcheckExecutor {
+/// The grammar checker executable
+const DIVVUN_CHECKER: &str = "divvun-checker";
+
+/// Upper bound of the delay between restarts of a failing divvun-checker
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Most pipeline and UI language combinations with running executors per language, the least
+/// recently used ones are stopped to make room for new ones
+const MAX_VARIANT_POOLS: usize = 8;
+
+/// How many checking timeouts a request waits for in all, so that the requests queued behind a
+/// slow one get checked after it instead of timing out with it
+const QUEUE_TIMEOUTS: u32 = 3;
+
+/// How a failing divvun-checker is restarted
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// Delay before the second restart in a row, doubled for every further one. The first
+    /// restart is immediate.
+    pub backoff: Duration,
+    /// Failures in a row after which the grammar checker is given up on
+    pub max_restarts: u32,
+}
+
+impl RestartPolicy {
+    fn delay(&self, failures: u32) -> Duration {
+        if failures <= 1 {
+            return Duration::from_secs(0);
+        }
+
+        let factor = 1u32.checked_shl(failures - 2).unwrap_or(u32::max_value());
+        self.backoff
+            .checked_mul(factor)
+            .map_or(MAX_RESTART_BACKOFF, |delay| delay.min(MAX_RESTART_BACKOFF))
+    }
+}
+
+/// A running divvun-checker process
+pub struct CheckerProcess {
     pub child: Child,
+    /// Lines to write to the child's stdin
+    pub input: Sender<String>,
+    /// Lines written by the child to its stdout
+    pub output: Receiver<String>,
+    /// The last lines written by the child to its stderr
+    pub stderr: StderrTail,
+}
+
+pub enum ProcessState {
+    Running(CheckerProcess),
+    /// The process failed and is started again by the first request after the delay
+    Restarting(Instant),
+    /// The process failed too many times in a row, it is started again once the data file,
+    /// last modified at the given time, is replaced
+    GivenUp(Option<SystemTime>),
+}
+
+pub struct GramcheckExecutor {
+    pub process: ProcessState,
+    /// Failures of the process since it last answered a request
+    pub failures: u32,
+    /// Set when the process is restarted for not answering in time. A slow request says little
+    /// about the process, so it isn't counted as a failure.
+    pub timed_out: bool,
+    pub restart_policy: RestartPolicy,
+    /// The executable run with the data file, `divvun-checker` outside of tests
+    pub program: String,
     pub path: String,
     pub language: String,
+    /// The divvun-checker pipeline to run, the archive's default if not set
+    pub pipeline: Option<String>,
+    /// The UI language of the messages, the archive's default if not set
+    pub ui_language: Option<String>,
+    pub timeout: Duration,
+    pub status: SharedWorkerStatus,
     pub terminated: bool,
 }
 
 impl GramcheckExecutor {
-    pub fn new(data_file_path: &str, language: &str) -> Result<Self, Error> {
-        let child = start_divvun_checker(data_file_path)?;
-
-        Ok(Self {
-            child,
+    /// Creates the executor and starts its process. If that fails, the executor is still created
+    /// and its process is restarted like one that died.
+    pub fn new(
+        data_file_path: &str,
+        language: &str,
+        variant: CheckerVariant,
+        timeout: Duration,
+        restart_policy: RestartPolicy,
+        status: SharedWorkerStatus,
+    ) -> Self {
+        let mut executor = Self {
+            process: ProcessState::Restarting(Instant::now()),
+            failures: 0,
+            timed_out: false,
+            restart_policy,
+            program: DIVVUN_CHECKER.to_owned(),
             path: data_file_path.to_owned(),
             language: language.to_owned(),
+            pipeline: variant.pipeline,
+            ui_language: variant.ui_language,
+            timeout,
+            status,
             terminated: false,
-        })
+        };
+
+        executor.start_process();
+        executor
+    }
+
+    fn start_process(&mut self) {
+        let variant = CheckerVariant {
+            pipeline: self.pipeline.clone(),
+            ui_language: self.ui_language.clone(),
+        };
+
+        match start_divvun_checker(&self.program, &self.path, &self.language, &variant) {
+            Ok(process) => {
+                self.status.write().pid = Some(process.child.id());
+                self.process = ProcessState::Running(process);
+            }
+            Err(e) => {
+                error!(
+                    "Failed to spawn divvun-checker for language `{}`: {}",
+                    &self.language, e
+                );
+                self.status.write().last_error = Some(format!(
+                    "Failed to spawn divvun-checker for language {}: {}",
+                    &self.language, e
+                ));
+                self.process_failed();
+            }
+        }
+    }
+
+    /// Counts a failure of the process, scheduling its restart or giving up on it
+    fn process_failed(&mut self) {
+        self.failures += 1;
+
+        if self.failures > self.restart_policy.max_restarts {
+            error!(
+                "divvun-checker for language `{}` failed {} times in a row, giving up until `{}` is replaced",
+                &self.language, self.failures, &self.path
+            );
+
+            self.status.write().unavailable = true;
+            self.process = ProcessState::GivenUp(modified_at(&self.path));
+            return;
+        }
+
+        let delay = self.restart_policy.delay(self.failures);
+        if delay == Duration::from_secs(0) {
+            self.start_process();
+        } else {
+            warn!(
+                "Restarting divvun-checker for language `{}` in {:?}",
+                &self.language, delay
+            );
+            self.process = ProcessState::Restarting(Instant::now() + delay);
+        }
+    }
+
+    /// Starts the process if it is due to be restarted, or else tells why it can't be used
+    fn ensure_running(&mut self) -> Result<(), ApiError> {
+        match self.process {
+            ProcessState::Running(_) => return Ok(()),
+            ProcessState::Restarting(at) if Instant::now() >= at => self.start_process(),
+            ProcessState::GivenUp(modified) if modified_at(&self.path) != modified => {
+                info!(
+                    "`{}` was replaced, restarting divvun-checker for language `{}`",
+                    &self.path, &self.language
+                );
+
+                self.failures = 0;
+                self.status.write().unavailable = false;
+                self.start_process();
+            }
+            _ => {}
+        }
+
+        match self.process {
+            ProcessState::Running(_) => Ok(()),
+            ProcessState::Restarting(at) => Err(ApiError::BackendUnavailable(format!(
+                "The grammar checker for language {} is restarting, try again in {} seconds",
+                &self.language,
+                at.saturating_duration_since(Instant::now()).as_secs() + 1
+            ))),
+            ProcessState::GivenUp(_) => Err(ApiError::BackendUnavailable(format!(
+                "The grammar checker for language {} is unavailable after failing {} times in a row",
+                &self.language, self.failures
+            ))),
+        }
     }
 
     fn kill_child(&mut self) {
-        match self.child.kill() {
+        let process = match &mut self.process {
+            ProcessState::Running(process) => process,
+            _ => return,
+        };
+
+        match process.child.kill() {
             Ok(_) => {
                 // This blocks and may cause issues if the child doesn't properly die
-                match self.child.wait() {
+                match process.child.wait() {
                     Ok(_) => debug!("Child killed"),
                     Err(e) => error!("Failed to kill child: {} while waiting", e),
                 }
             }
             Err(e) => error!("Failed to kill child: {}", e),
         };
+
+        let mut status = self.status.write();
+        status.pid = None;
+        status.stderr = process.stderr.finish();
     }
 }
 
-fn start_divvun_checker(data_file_path: &str) -> Result<Child, Error> {
-    let process = Command::new("divvun-checker")
-        .arg("-a")
-        .arg(data_file_path)
+/// When the file was last modified, if it can be told
+fn modified_at(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Remembers the error for the status endpoint before returning it
+fn record_error(status: &SharedWorkerStatus, error: ApiError) -> ApiError {
+    status.write().last_error = Some(error.to_string());
+    error
+}
+
+/// The pipeline and message language a divvun-checker process runs with
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CheckerVariant {
+    pub pipeline: Option<String>,
+    pub ui_language: Option<String>,
+}
+
+fn start_divvun_checker(
+    program: &str,
+    data_file_path: &str,
+    language: &str,
+    variant: &CheckerVariant,
+) -> Result<CheckerProcess, Error> {
+    let mut command = Command::new(program);
+    command.arg("-a").arg(data_file_path);
+
+    if let Some(pipeline) = &variant.pipeline {
+        command.arg("-n").arg(pipeline);
+    }
+
+    if let Some(ui_language) = &variant.ui_language {
+        command.arg("-l").arg(ui_language);
+    }
+
+    let mut process = command
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()?;
 
-    Ok(process)
+    let stdin = process
+        .stdin
+        .take()
+        .ok_or_else(|| Error::new(ErrorKind::Other, "Failed to open stdin"))?;
+    let stdout = process
+        .stdout
+        .take()
+        .ok_or_else(|| Error::new(ErrorKind::Other, "Failed to open stdout"))?;
+    let stderr = process
+        .stderr
+        .take()
+        .ok_or_else(|| Error::new(ErrorKind::Other, "Failed to open stderr"))?;
+
+    Ok(CheckerProcess {
+        child: process,
+        input: write_lines(stdin),
+        output: read_lines(stdout),
+        stderr: StderrTail::start(stderr, language),
+    })
+}
+
+/// Writes to the child's stdin on a separate thread, so that a child that stopped reading its
+/// input blocks the thread rather than the executor and waiting for its response still times out
+fn write_lines(mut stdin: ChildStdin) -> Sender<String> {
+    let (tx, rx) = channel::<String>();
+
+    thread::spawn(move || {
+        for line in rx {
+            if let Err(e) = stdin
+                .write_all(line.as_bytes())
+                .and_then(|_| stdin.write_all(b"\n"))
+            {
+                debug!("Stopped writing divvun-checker input: {}", e);
+                break;
+            }
+        }
+    });
+
+    tx
+}
+
+/// Reads the child's stdout on a separate thread so that waiting for a response can time out
+fn read_lines(stdout: ChildStdout) -> Receiver<String> {
+    let (tx, rx) = channel();
+
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    debug!("Stopped reading divvun-checker output: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    rx
+}
+
+/// Number of stderr lines kept for the status endpoint
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Number of characters kept of a stderr line, the rest is cut off
+const STDERR_LINE_CHARS: usize = 500;
+
+/// Drains the child's stderr on a separate thread, so that the child doesn't block on a full
+/// pipe, logging every line and keeping the last ones
+pub struct StderrTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl StderrTail {
+    fn start(stderr: ChildStderr, language: &str) -> Self {
+        let lines = Arc::new(Mutex::new(VecDeque::with_capacity(STDERR_TAIL_LINES)));
+        let tail = Arc::clone(&lines);
+        let language = language.to_owned();
+
+        let reader = thread::spawn(move || {
+            for line in BufReader::new(stderr).lines() {
+                match line {
+                    Ok(line) => {
+                        warn!("divvun-checker for `{}`: {}", &language, &line);
+                        push_line(&mut tail.lock(), line);
+                    }
+                    Err(e) => {
+                        debug!("Stopped reading divvun-checker stderr: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        StderrTail {
+            lines,
+            reader: Some(reader),
+        }
+    }
+
+    /// The last lines of a child that has exited, once its stderr has been read to the end
+    fn finish(&mut self) -> Vec<String> {
+        if let Some(reader) = self.reader.take() {
+            if reader.join().is_err() {
+                error!("Thread reading divvun-checker stderr panicked");
+            }
+        }
+
+        self.lines.lock().iter().cloned().collect()
+    }
+}
+
+/// Adds the line to the end of the tail, dropping the first line of a full tail
+fn push_line(tail: &mut VecDeque<String>, mut line: String) {
+    if let Some((end, _)) = line.char_indices().nth(STDERR_LINE_CHARS) {
+        line.truncate(end);
+        line.push('…');
+    }
+
+    if tail.len() == STDERR_TAIL_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(line);
 }
 
 impl Actor for GramcheckExecutor {
@@ -71,20 +423,140 @@ impl Supervised for GramcheckExecutor {
             // Killing previous child. Reusing the child would be more eco-friendly,
             // but there seems no reliable way to check the status of the child
             self.kill_child();
-            self.child = match start_divvun_checker(&self.path) {
-                Ok(child) => child,
-                Err(e) => {
-                    error!("Failed to spawn child for language `{}`!", &self.language);
-                    panic!(e)
-                }
+            self.status.write().restarts += 1;
+            GRAMCHECKER_RESTARTS
+                .with_label_values(&[&self.language])
+                .inc();
+
+            if self.timed_out {
+                self.timed_out = false;
+                self.start_process();
+            } else {
+                self.process_failed();
             }
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct GramcheckRequest {
     pub text: String,
+    /// Error tags that should not be reported
+    #[serde(default)]
+    pub ignore_tags: Vec<String>,
+    /// If set, only errors with these tags are reported
+    pub only_tags: Option<Vec<String>>,
+    /// The divvun-checker pipeline to run, the language's default if not set
+    pub pipeline: Option<String>,
+    /// The UI language of the error titles and descriptions, the grammar checker's default if
+    /// it has no messages in the language
+    pub ui_language: Option<String>,
+    /// The tags matched against the language's regex categories, see `compile_tags`
+    #[serde(skip)]
+    pub tag_filter: Option<TagFilter>,
+}
+
+impl GramcheckRequest {
+    /// Looks the tags up among the language's regex categories once for the whole request.
+    /// Without it, every tag is matched as is.
+    pub fn compile_tags(&mut self, language: &str, preferences: &GramcheckPreferences) {
+        let preferences = match preferences.get(language) {
+            Some(Ok(preferences)) => Some(preferences),
+            _ => None,
+        };
+
+        self.tag_filter = Some(TagFilter::new(
+            &self.ignore_tags,
+            self.only_tags.as_deref(),
+            preferences,
+        ));
+    }
+
+    /// Checks that the requested pipeline and every requested tag are offered by the language's
+    /// grammar checker
+    pub fn validate(
+        &self,
+        language: &str,
+        preferences: &GramcheckPreferences,
+    ) -> Result<(), ApiError> {
+        // Every pipeline starts its own executors, so only the listed ones are accepted
+        if let Some(pipeline) = &self.pipeline {
+            match preferences.get(language) {
+                Some(Ok(preferences)) if preferences.pipelines.contains(pipeline) => {}
+                Some(Ok(preferences)) => {
+                    return Err(ApiError::InvalidInput(format!(
+                        "Unknown pipeline for language {}: {}, available are {}",
+                        language,
+                        pipeline,
+                        preferences.pipelines.join(", ")
+                    )));
+                }
+                Some(Err(e)) => {
+                    return Err(ApiError::InvalidInput(format!(
+                        "Pipelines of language {} can't be listed: {}",
+                        language, e
+                    )));
+                }
+                None => {
+                    return Err(ApiError::InvalidInput(format!(
+                        "No pipelines are listed for language {}",
+                        language
+                    )));
+                }
+            }
+        }
+
+        let preferences = match preferences.get(language) {
+            Some(Ok(preferences)) => preferences,
+            // Missing languages are reported by the grammar checker itself, and the tags can't
+            // be checked without the preferences
+            _ => return Ok(()),
+        };
+
+        let unknown_tags: Vec<&str> = self
+            .ignore_tags
+            .iter()
+            .chain(self.only_tags.iter().flatten())
+            .filter(|tag| !preferences.is_known_tag(tag))
+            .map(|tag| tag.as_str())
+            .collect();
+
+        if !unknown_tags.is_empty() {
+            return Err(ApiError::InvalidInput(format!(
+                "Unknown error tags for language {}: {}",
+                language,
+                unknown_tags.join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Settles on the UI language of the messages: the requested one, or else the first of the
+    /// `Accept-Language` header's, that the grammar checker has messages in
+    pub fn negotiate_ui_language(
+        &mut self,
+        language: &str,
+        preferences: &GramcheckPreferences,
+        accept_languages: &[String],
+    ) {
+        let preferences = match preferences.get(language) {
+            Some(Ok(preferences)) => preferences,
+            _ => {
+                self.ui_language = None;
+                return;
+            }
+        };
+
+        let requested: Vec<&str> = self
+            .ui_language
+            .iter()
+            .chain(accept_languages)
+            .map(String::as_str)
+            .collect();
+
+        self.ui_language = preferences.negotiate_ui_language(&requested);
+    }
 }
 
 impl Message for GramcheckRequest {
@@ -94,51 +566,132 @@ impl Message for GramcheckRequest {
 impl Handler<GramcheckRequest> for GramcheckExecutor {
     type Result = Result<GramcheckResponse, ApiError>;
 
-    fn handle(&mut self, msg: GramcheckRequest, ctx: &mut Self::Context) -> Self::Result {
-        let stdin = match self.child.stdin.as_mut() {
-            Some(r) => r,
+    fn handle(&mut self, mut msg: GramcheckRequest, ctx: &mut Self::Context) -> Self::Result {
+        self.ensure_running()?;
+
+        let tag_filter = msg
+            .tag_filter
+            .take()
+            .unwrap_or_else(|| TagFilter::new(&msg.ignore_tags, msg.only_tags.as_deref(), None));
+
+        let process = match &mut self.process {
+            ProcessState::Running(process) => process,
             _ => {
-                ctx.stop();
-                return Err(ApiError {
-                    message: "Failed to open stdin".into(),
-                });
+                return Err(ApiError::BackendUnavailable(format!(
+                    "divvun-checker for language {} is not running",
+                    &self.language
+                )))
             }
         };
 
-        let stdout = match self.child.stdout.as_mut() {
-            Some(r) => r,
-            _ => {
+        let deadline = Instant::now() + self.timeout;
+        let mut errs = vec![];
+
+        // divvun-checker handles one paragraph per line, so every line is checked separately
+        // and the resulting indices are shifted to be relative to the whole text
+        for (offset, paragraph) in paragraphs(&msg.text) {
+            // The paragraph is written while waiting for its response, so the deadline covers both
+            if process.input.send(paragraph.to_owned()).is_err() {
+                // The input can't be written anymore, restart the runner
                 ctx.stop();
-                return Err(ApiError {
-                    message: "Failed to open stdout".into(),
-                });
+                return Err(record_error(
+                    &self.status,
+                    ApiError::BackendUnavailable(format!(
+                        "Failed to write to divvun-checker for language {}",
+                        &self.language
+                    )),
+                ));
             }
-        };
 
-        let mut stdout = BufReader::new(stdout);
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let line = match process.output.recv_timeout(remaining) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    warn!(
+                        "divvun-checker for `{}` did not respond within {:?}, restarting it. Input: '{}'",
+                        &self.language, self.timeout, paragraph
+                    );
 
-        let cleaned_msg = msg.text.split('\n').next().ok_or_else(|| ApiError {
-            message: "Invalid input".into(),
-        })?;
+                    // Restarting the runner kills the hung child
+                    self.timed_out = true;
+                    ctx.stop();
+                    return Err(record_error(
+                        &self.status,
+                        ApiError::Timeout(format!(
+                            "Grammar checking timed out for language {}",
+                            &self.language
+                        )),
+                    ));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    ctx.stop();
+                    return Err(record_error(
+                        &self.status,
+                        ApiError::BackendUnavailable(format!(
+                            "divvun-checker for language {} closed its output",
+                            &self.language
+                        )),
+                    ));
+                }
+            };
 
-        let mut line = String::new();
+            let response: GramcheckResponse = match serde_json::from_str(&line) {
+                Ok(response) => response,
+                Err(err) => {
+                    return Err(record_error(
+                        &self.status,
+                        ApiError::Internal(format!("error: {:?}, line: '{}'", &err, &line)),
+                    ));
+                }
+            };
 
-        if let Err(err) = stdin
-            .write_all(cleaned_msg.as_bytes())
-            .and_then(|_| stdin.write_all(b"\n"))
-            .and_then(|_| stdout.read_line(&mut line))
-        {
-            // If anything here fails, restart the runner
-            ctx.stop();
-            return Err(err.into());
+            errs.extend(
+                response
+                    .errs
+                    .into_iter()
+                    .filter(|err| tag_filter.is_reported(&err.error_code))
+                    .map(|mut err| {
+                        err.start_index += offset;
+                        err.end_index += offset;
+                        err
+                    }),
+            );
         }
 
-        serde_json::from_str(&line).map_err(|err| ApiError {
-            message: format!("error: {:?}, line: '{}'", &err, &line),
+        // The process is working, so a later failure starts the backoff over
+        self.failures = 0;
+
+        WORDS_PROCESSED
+            .with_label_values(&["grammar", &self.language])
+            .inc_by(msg.text.word_indices().count() as i64);
+
+        Ok(GramcheckResponse {
+            text: msg.text,
+            errs,
         })
     }
 }
 
+/// Splits the text into its non-empty lines, paired with the character offset
+/// at which each line starts within the text
+fn paragraphs(text: &str) -> Vec<(u32, &str)> {
+    let mut offset = 0;
+    let mut paragraphs = vec![];
+
+    for line in text.split('\n') {
+        let paragraph = line.trim_end_matches('\r');
+
+        if !paragraph.trim().is_empty() {
+            paragraphs.push((offset, paragraph));
+        }
+
+        // Account for the newline character that was split on
+        offset += line.chars().count() as u32 + 1;
+    }
+
+    paragraphs
+}
+
 pub struct Die;
 
 impl Message for Die {
@@ -166,7 +719,10 @@ impl Handler<Die> for GramcheckExecutor {
 
 #[derive(Deserialize, Serialize)]
 pub struct GramcheckPreferencesResponse {
+    /// Descriptions of the error tags, kept for clients predating `categories`
     pub error_tags: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub preferences: GrammarPreferences,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -186,8 +742,201 @@ pub struct GramcheckResponse {
     pub errs: Vec<GramcheckErrResponse>,
 }
 
+/// The grammar checker of a language, with a pool of executors for every pipeline and UI
+/// language in use
+pub struct LanguageGramcheckers {
+    path: String,
+    pool_size: usize,
+    /// The pipeline of requests that don't choose one, the archive's default if not set
+    default_pipeline: Option<String>,
+    pools: HashMap<CheckerVariant, VariantPool>,
+}
+
+struct VariantPool {
+    pool: ExecutorPool<GramcheckExecutor>,
+    last_used: Mutex<Instant>,
+}
+
+impl VariantPool {
+    fn new(pool: ExecutorPool<GramcheckExecutor>) -> Self {
+        VariantPool {
+            pool,
+            last_used: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn next(&self) -> Addr<GramcheckExecutor> {
+        *self.last_used.lock() = Instant::now();
+        self.pool.next().clone()
+    }
+
+    /// Stops the executors, and then the arbiters they ran in
+    fn stop(self) {
+        let deaths = join_all(
+            self.pool
+                .executors()
+                .iter()
+                .map(|executor| executor.send(Die))
+                .collect::<Vec<_>>(),
+        );
+
+        Arbiter::spawn(deaths.then(move |_| {
+            self.pool.stop();
+            Ok(())
+        }));
+    }
+}
+
+impl LanguageGramcheckers {
+    /// Makes room for another pool, stopping the least recently used one other than the default
+    fn evict(&mut self, language: &str) {
+        if self.pools.len() < MAX_VARIANT_POOLS {
+            return;
+        }
+
+        let default = CheckerVariant {
+            pipeline: self.default_pipeline.clone(),
+            ui_language: None,
+        };
+        let least_recent = self
+            .pools
+            .iter()
+            .filter(|(variant, _)| **variant != default)
+            .min_by_key(|(_, pool)| *pool.last_used.lock())
+            .map(|(variant, _)| variant.clone());
+
+        if let Some(variant) = least_recent {
+            info!(
+                "Stopping Grammar Checker for {} with pipeline {} and UI language {}",
+                language,
+                variant.pipeline.as_deref().unwrap_or("(default)"),
+                variant.ui_language.as_deref().unwrap_or("(default)")
+            );
+
+            if let Some(pool) = self.pools.remove(&variant) {
+                pool.stop();
+            }
+        }
+    }
+
+    fn variant(&self, request: &GramcheckRequest) -> CheckerVariant {
+        CheckerVariant {
+            pipeline: request
+                .pipeline
+                .clone()
+                .or_else(|| self.default_pipeline.clone()),
+            ui_language: request.ui_language.clone(),
+        }
+    }
+}
+
 pub struct AsyncGramchecker {
-    pub gramcheckers: Arc<RwLock<HashMap<String, Addr<GramcheckExecutor>>>>,
+    pub gramcheckers: Arc<RwLock<HashMap<String, LanguageGramcheckers>>>,
+    pub pool_size: usize,
+    pub timeout: Duration,
+    pub restart_policy: RestartPolicy,
+    pub languages: Languages,
+}
+
+fn language_not_found(language: &str) -> ApiError {
+    ApiError::LanguageNotFound(format!(
+        "No grammar checker available for language {}",
+        language
+    ))
+}
+
+impl AsyncGramchecker {
+    fn start_pool(
+        &self,
+        language: &str,
+        path: &str,
+        pool_size: usize,
+        variant: CheckerVariant,
+    ) -> ExecutorPool<GramcheckExecutor> {
+        info!(
+            "Starting Grammar Checker for {} with pipeline {} and UI language {}",
+            language,
+            variant.pipeline.as_deref().unwrap_or("(default)"),
+            variant.ui_language.as_deref().unwrap_or("(default)")
+        );
+
+        let gramchecker_path = path.to_owned();
+        let owned_language = language.to_owned();
+        let timeout = self.timeout;
+        let restart_policy = self.restart_policy;
+        ExecutorPool::start(pool_size, path, move |status| {
+            GramcheckExecutor::new(
+                &gramchecker_path,
+                &owned_language,
+                variant.clone(),
+                timeout,
+                restart_policy,
+                status,
+            )
+        })
+    }
+
+    /// The next executor for the request, starting the executors of its pipeline and UI language
+    /// on their first use
+    fn executor(
+        &self,
+        language: &str,
+        request: &GramcheckRequest,
+    ) -> Result<Addr<GramcheckExecutor>, ApiError> {
+        {
+            let gramcheckers = self.gramcheckers.read();
+            let checkers = gramcheckers
+                .get(language)
+                .ok_or_else(|| language_not_found(language))?;
+
+            if let Some(pool) = checkers.pools.get(&checkers.variant(request)) {
+                return Ok(pool.next());
+            }
+        }
+
+        let mut gramcheckers = self.gramcheckers.write();
+        let checkers = gramcheckers
+            .get_mut(language)
+            .ok_or_else(|| language_not_found(language))?;
+
+        let variant = checkers.variant(request);
+        let path = checkers.path.clone();
+        let pool_size = checkers.pool_size;
+
+        // Another request may have started the executors since the read lock was released
+        if !checkers.pools.contains_key(&variant) {
+            checkers.evict(language);
+        }
+        let pool = checkers.pools.entry(variant.clone()).or_insert_with(|| {
+            VariantPool::new(self.start_pool(language, &path, pool_size, variant))
+        });
+
+        Ok(pool.next())
+    }
+}
+
+/// Sends the request to the executor. The executor times out checking it by itself, so the
+/// request only times out here if it waited too long for the requests queued before it.
+fn send_request(
+    executor: &Addr<GramcheckExecutor>,
+    message: GramcheckRequest,
+    language: String,
+    timeout: Duration,
+) -> Box<dyn Future<Item = GramcheckResponse, Error = ApiError>> {
+    executor
+        .send(message)
+        .timeout(timeout * QUEUE_TIMEOUTS)
+        .map_err(move |err| match err {
+            MailboxError::Timeout => ApiError::Timeout(format!(
+                "Timed out waiting for a grammar checker for language {}",
+                &language
+            )),
+            err => ApiError::BackendUnavailable(format!(
+                "Something failed in the message delivery process for language {}: {}",
+                &language, err
+            )),
+        })
+        .unhoist()
 }
 
 impl LanguageSuggestions for AsyncGramchecker {
@@ -199,45 +948,41 @@ impl LanguageSuggestions for AsyncGramchecker {
         message: Self::Request,
         language: &str,
     ) -> Box<dyn Future<Item = Self::Response, Error = ApiError>> {
-        let gramcheckers = self.gramcheckers.read();
-
-        let gramchecker = match gramcheckers.get(language) {
-            Some(s) => s,
-            None => {
-                return Box::new(err(ApiError {
-                    message: format!("No grammar checker available for language {}", &language),
-                }));
-            }
+        let gramchecker = match self.executor(language, &message) {
+            Ok(gramchecker) => gramchecker,
+            Err(e) => return Box::new(err(e)),
         };
 
-        let language = language.to_owned();
-
-        Box::new(
-            gramchecker
-                .send(message)
-                .map_err(move |err| ApiError {
-                    message: format!(
-                        "Something failed in the message delivery process for language {}: {}",
-                        &language, err
-                    ),
-                })
-                .unhoist(),
-        )
+        send_request(&gramchecker, message, language.to_owned(), self.timeout)
     }
 
     fn add(&self, language: &str, path: &str) -> Box<dyn Future<Item = (), Error = ApiError>> {
         info!("Adding Grammar Checker for {}", language);
 
-        let mut gramcheckers = self.gramcheckers.write();
+        let settings = language_settings(&self.languages, language);
+        let pool_size = settings.grammar_pool_size.unwrap_or(self.pool_size);
+        let pipeline = settings.grammar_pipeline;
 
-        let gramchecker_path = path.to_owned();
-        let owned_language = language.to_owned();
-        let gramchecker = actix::Supervisor::start_in_arbiter(&actix::Arbiter::new(), move |_| {
-            GramcheckExecutor::new(&gramchecker_path, &owned_language)
-                .expect(&format!("not found: {}", &gramchecker_path))
-        });
+        let variant = CheckerVariant {
+            pipeline: pipeline.clone(),
+            ui_language: None,
+        };
+        let mut pools = HashMap::new();
+        pools.insert(
+            variant.clone(),
+            VariantPool::new(self.start_pool(language, path, pool_size, variant)),
+        );
 
-        gramcheckers.insert(language.to_owned(), gramchecker);
+        let mut gramcheckers = self.gramcheckers.write();
+        gramcheckers.insert(
+            language.to_owned(),
+            LanguageGramcheckers {
+                path: path.to_owned(),
+                pool_size,
+                default_pipeline: pipeline,
+                pools,
+            },
+        );
 
         Box::new(ok(()))
     }
@@ -250,77 +995,361 @@ impl LanguageSuggestions for AsyncGramchecker {
         let gramchecker = match gramcheckers.remove(language) {
             Some(s) => s,
             None => {
-                return Box::new(err(ApiError {
-                    message: format!("No grammar checker available for language {}", &language),
-                }));
+                return Box::new(err(language_not_found(language)));
             }
         };
 
         let cloned_gramcheckers = Arc::clone(&self.gramcheckers);
         let language = language.to_owned();
 
-        Box::new(
+        let deaths = join_all(
             gramchecker
-                .send(Die)
-                .map_err(move |err| {
-                    // Put the address back in since we failed to send the die message
-                    let mut cloned_gramcheckers = cloned_gramcheckers.write();
-                    cloned_gramcheckers.insert(language.clone(), gramchecker);
-
-                    ApiError {
-                        message: format!(
-                            "Something failed in the message delivery process for language {}: {}",
-                            &language, err
-                        ),
-                    }
+                .pools
+                .values()
+                .flat_map(|pool| {
+                    pool.pool
+                        .executors()
+                        .iter()
+                        .map(|executor| executor.send(Die))
                 })
-                .and_then(|_| ok(())),
-        )
-    }
-}
-
-pub fn list_preferences(data_file_path: &str) -> Result<BTreeMap<String, String>, Error> {
-    let process = Command::new("divvun-checker")
-        .arg("-a")
-        .arg(data_file_path)
-        .arg("-p")
-        .output()
-        .expect("failed to run divvun-checker");
-
-    let regex = Regex::new(r"- \[.\] ([^\s+]+)\s+(.+)$").expect("valid regex");
-    let toggle_separator = "==== Toggles: ====";
-
-    let categories: BTreeMap<String, String> = String::from_utf8(process.stdout)
-        .map_err(to_io_err)?
-        .lines()
-        .skip_while(|&l| l != toggle_separator)
-        .skip(1)
-        // temporary solution
-        .skip_while(|&l| l != toggle_separator)
-        .skip(1)
-        .map(|l| {
-            regex
-                .captures(&l)
-                .map(|c| (c[1].to_owned(), c[2].to_owned()))
-        })
-        .take_while(|m| m.is_some())
-        .map(|m| m.unwrap())
-        .filter(|m| m.0 != "[regex]")
-        .collect();
+                .collect::<Vec<_>>(),
+        );
 
-    Ok(categories)
-}
+        Box::new(deaths.then(move |result| match result {
+            Ok(_) => {
+                // The executors are gone, so the threads they ran on can go too
+                for pool in gramchecker.pools.values() {
+                    pool.pool.stop();
+                }
+                Ok(())
+            }
+            Err(err) => {
+                // Put the pools back in since we failed to send the die message
+                let mut cloned_gramcheckers = cloned_gramcheckers.write();
+                cloned_gramcheckers.insert(language.clone(), gramchecker);
 
-fn to_io_err(cause: impl ToString) -> Error {
-    std::io::Error::new(std::io::ErrorKind::Other, cause.to_string())
+                Err(ApiError::BackendUnavailable(format!(
+                    "Something failed in the message delivery process for language {}: {}",
+                    &language, err
+                )))
+            }
+        }))
+    }
+
+    fn has_language(&self, language: &str) -> bool {
+        self.gramcheckers.read().contains_key(language)
+    }
+
+    fn status(&self) -> Vec<LanguageStatus> {
+        let lock = self.gramcheckers.read();
+
+        lock.iter()
+            .flat_map(|(language, checkers)| {
+                checkers
+                    .pools
+                    .iter()
+                    .map(move |(variant, pool)| LanguageStatus {
+                        pipeline: variant.pipeline.clone(),
+                        ui_language: variant.ui_language.clone(),
+                        ..pool.pool.status(language)
+                    })
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use actix::prelude::*;
+    use actix::SystemRunner;
+    use futures::future::{self, Future};
+    use parking_lot::RwLock;
     use serde_json::json;
 
+    use std::collections::VecDeque;
+    use std::env;
+    use std::fs;
+    use std::process::{self, Command, Stdio};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        paragraphs, push_line, send_request, GramcheckExecutor, GramcheckPreferences,
+        GramcheckRequest, ProcessState, RestartPolicy, StderrTail, STDERR_LINE_CHARS,
+        STDERR_TAIL_LINES,
+    };
+    use crate::error::ApiError;
+    use crate::language::preferences::PreferencesError;
+    use crate::server::status::{SharedWorkerStatus, WorkerStatus};
+
+    /// Answers every line without errors, and never answers `hang`
+    const HANGING_CHECKER: &str = r#"
+        while read line; do
+            case "$line" in
+                hang) while :; do :; done ;;
+                *) echo "{\"text\":\"$line\",\"errs\":[]}" ;;
+            esac
+        done
+    "#;
+
+    /// Writes a shell script standing in for divvun-checker. The script is passed to `sh` like
+    /// a data file, as `sh -a <script>`, so it doesn't need to be executable.
+    fn fake_checker(name: &str, script: &str) -> String {
+        let path = env::temp_dir().join(format!("divvun-api-{}-{}.sh", name, process::id()));
+        fs::write(&path, script).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// An executor running the script, started by its first request
+    fn executor(
+        script_path: &str,
+        timeout: Duration,
+        max_restarts: u32,
+    ) -> (GramcheckExecutor, SharedWorkerStatus) {
+        let status = Arc::new(RwLock::new(WorkerStatus::default()));
+        let executor = GramcheckExecutor {
+            process: ProcessState::Restarting(Instant::now()),
+            failures: 0,
+            timed_out: false,
+            restart_policy: RestartPolicy {
+                backoff: Duration::from_millis(10),
+                max_restarts,
+            },
+            program: "sh".to_owned(),
+            path: script_path.to_owned(),
+            language: "se".to_owned(),
+            pipeline: None,
+            ui_language: None,
+            timeout,
+            status: Arc::clone(&status),
+            terminated: false,
+        };
+
+        (executor, status)
+    }
+
+    /// Starts the executor in the system, for checking requests with `check`
+    fn start(system: &mut SystemRunner, executor: GramcheckExecutor) -> Addr<GramcheckExecutor> {
+        system
+            .block_on(future::lazy(move || {
+                Ok::<_, ()>(Supervisor::start(move |_| executor))
+            }))
+            .unwrap()
+    }
+
+    fn check(
+        system: &mut SystemRunner,
+        executor: &Addr<GramcheckExecutor>,
+        text: &str,
+    ) -> Result<String, ApiError> {
+        system
+            .block_on(send_request(
+                executor,
+                request(text),
+                "se".to_owned(),
+                Duration::from_secs(1),
+            ))
+            .map(|response| response.text)
+    }
+
+    fn request(text: &str) -> GramcheckRequest {
+        GramcheckRequest {
+            text: text.to_owned(),
+            ignore_tags: vec![],
+            only_tags: None,
+            pipeline: None,
+            ui_language: None,
+            tag_filter: None,
+        }
+    }
+
     #[test]
     fn test_foo() {
         let _some_data = json!({"errs":[["heno",0,4,"typo","Čállinmeattáhus",[]]],"text":"heno."});
     }
+
+    #[test]
+    fn test_paragraphs() {
+        let text = "sup  ney\r\n\nČáhcesullos lea\n  \nheno.";
+
+        assert_eq!(
+            paragraphs(text),
+            vec![(0, "sup  ney"), (11, "Čáhcesullos lea"), (30, "heno.")]
+        );
+    }
+
+    #[test]
+    fn test_restart_delay() {
+        let policy = RestartPolicy {
+            backoff: Duration::from_millis(500),
+            max_restarts: 5,
+        };
+
+        assert_eq!(policy.delay(1), Duration::from_secs(0));
+        assert_eq!(policy.delay(2), Duration::from_millis(500));
+        assert_eq!(policy.delay(4), Duration::from_secs(2));
+        assert_eq!(policy.delay(40), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_validate_without_preferences() {
+        let mut preferences = GramcheckPreferences::new();
+        preferences.insert(
+            "se".to_owned(),
+            Err(PreferencesError::Spawn("not found".to_owned())),
+        );
+
+        let mut request = GramcheckRequest {
+            text: "heno.".to_owned(),
+            ignore_tags: vec![],
+            only_tags: None,
+            pipeline: None,
+            ui_language: None,
+            tag_filter: None,
+        };
+        assert!(request.validate("se", &preferences).is_ok());
+
+        request.pipeline = Some("smegram-dev".to_owned());
+        match request.validate("se", &preferences) {
+            Err(ApiError::InvalidInput(_)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        // Without any preferences no pipeline is listed either
+        match request.validate("sma", &preferences) {
+            Err(ApiError::InvalidInput(_)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        request.pipeline = None;
+        assert!(request.validate("sma", &preferences).is_ok());
+    }
+
+    #[test]
+    fn test_hanging_checker() {
+        let script = fake_checker("hanging", HANGING_CHECKER);
+        let (executor, status) = executor(&script, Duration::from_millis(300), 5);
+
+        let (hung, queued) = System::new("test")
+            .block_on(future::lazy(move || {
+                let executor = Supervisor::start(move |_| executor);
+
+                // The second request waits for the first one to time out
+                let hung = send_request(
+                    &executor,
+                    request("hang"),
+                    "se".to_owned(),
+                    Duration::from_millis(300),
+                );
+                let queued = send_request(
+                    &executor,
+                    request("heno."),
+                    "se".to_owned(),
+                    Duration::from_millis(300),
+                );
+
+                hung.then(Ok::<_, ()>).join(queued.then(Ok))
+            }))
+            .unwrap();
+
+        match hung {
+            Err(ApiError::Timeout(_)) => {}
+            result => panic!("unexpected result {:?}", result.map(|r| r.text)),
+        }
+        // Answered by the restarted child
+        assert_eq!(queued.unwrap().text, "heno.");
+
+        let status = status.read();
+        assert_eq!(status.restarts, 1);
+        assert!(status.pid.is_some());
+        assert!(!status.unavailable);
+        let _ = fs::remove_file(script);
+    }
+
+    #[test]
+    fn test_given_up_checker() {
+        let script = fake_checker("dying", "exit 1\n");
+        let (executor, status) = executor(&script, Duration::from_secs(1), 2);
+        let mut system = System::new("test");
+        let executor = start(&mut system, executor);
+
+        // Every request fails, also while the checker waits to be restarted, until the checker
+        // has failed more than `max_restarts` times in a row
+        let mut given_up = None;
+        for _ in 0..100 {
+            match check(&mut system, &executor, "heno.") {
+                Err(ApiError::BackendUnavailable(message)) => {
+                    if message.contains("unavailable") {
+                        given_up = Some(message);
+                        break;
+                    }
+                }
+                result => panic!("unexpected result {:?}", result),
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(
+            given_up.as_deref(),
+            Some(
+                "The grammar checker for language se is unavailable after failing 3 times in a row"
+            )
+        );
+        assert!(status.read().unavailable);
+        assert_eq!(status.read().restarts, 3);
+
+        // Not started again until the data file is replaced
+        thread::sleep(Duration::from_millis(50));
+        match check(&mut system, &executor, "heno.") {
+            Err(e) => assert_eq!(e.code(), "backend_unavailable"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(status.read().restarts, 3);
+
+        fs::write(&script, HANGING_CHECKER).unwrap();
+        assert_eq!(check(&mut system, &executor, "heno.").unwrap(), "heno.");
+        assert!(!status.read().unavailable);
+
+        let _ = fs::remove_file(script);
+    }
+
+    #[test]
+    fn test_push_line() {
+        let mut tail = VecDeque::new();
+        for i in 0..STDERR_TAIL_LINES + 5 {
+            push_line(&mut tail, format!("line {}", i));
+        }
+
+        assert_eq!(tail.len(), STDERR_TAIL_LINES);
+        assert_eq!(tail.front().map(String::as_str), Some("line 5"));
+        assert_eq!(
+            tail.back().map(String::as_str),
+            Some(format!("line {}", STDERR_TAIL_LINES + 4).as_str())
+        );
+
+        push_line(&mut tail, "á".repeat(STDERR_LINE_CHARS + 1));
+        let cut = tail.back().unwrap();
+        assert_eq!(cut.chars().count(), STDERR_LINE_CHARS + 1);
+        assert!(cut.ends_with("á…"));
+
+        push_line(&mut tail, "á".repeat(STDERR_LINE_CHARS));
+        assert_eq!(tail.back().unwrap(), &"á".repeat(STDERR_LINE_CHARS));
+    }
+
+    #[test]
+    fn test_stderr_tail() {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"i=1; while [ $i -le 30 ]; do echo "line $i" >&2; i=$((i + 1)); done"#)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut tail = StderrTail::start(child.stderr.take().unwrap(), "se");
+        child.wait().unwrap();
+        let lines = tail.finish();
+
+        assert_eq!(lines.len(), STDERR_TAIL_LINES);
+        assert_eq!(lines.first().map(String::as_str), Some("line 11"));
+        assert_eq!(lines.last().map(String::as_str), Some("line 30"));
+    }
 }
@@ -1,27 +1,60 @@
-use actix_web::{web, HttpResponse};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_web::http::header;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
 
 use futures::future::{result, Future};
 
+use crate::error::ApiError;
+use crate::server::auth::Client;
 use crate::server::state::State;
 
+use super::batch::{check_batch, BatchItem};
 use super::data_files::{
     available_languages, AvailableLanguagesByType, AvailableLanguagesResponse, DataFileType,
 };
 use super::grammar::{GramcheckPreferencesResponse, GramcheckRequest};
 use super::hyphenation::HyphenationRequest;
+use super::preferences::accept_languages;
 use super::speller::SpellerRequest;
 
+/// The UI languages the client accepts, most preferred first
+pub fn request_ui_languages(req: &HttpRequest) -> Vec<String> {
+    req.headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map(accept_languages)
+        .unwrap_or_default()
+}
+
 pub fn get_available_languages_handler(
     state: web::Data<State>,
 ) -> actix_web::Result<web::Json<AvailableLanguagesResponse>> {
     let config = &state.config;
 
-    let grammar_checker_langs =
-        available_languages(config.data_file_dir.as_path(), DataFileType::Grammar);
-    let spell_checker_langs =
-        available_languages(config.data_file_dir.as_path(), DataFileType::Spelling);
-    let hyphenation_langs =
-        available_languages(config.data_file_dir.as_path(), DataFileType::Hyphenation);
+    let languages = |data_type| -> HashMap<String, String> {
+        if !config.services.enabled(data_type) {
+            return HashMap::new();
+        }
+
+        available_languages(config.data_file_dir.as_path(), data_type)
+            .into_iter()
+            .filter(|(code, _)| config.language_enabled(code))
+            .map(|(code, name)| {
+                let name = config
+                    .languages
+                    .get(&code)
+                    .and_then(|settings| settings.display_name.clone())
+                    .unwrap_or(name);
+                (code, name)
+            })
+            .collect()
+    };
+
+    let grammar_checker_langs = languages(DataFileType::Grammar);
+    let spell_checker_langs = languages(DataFileType::Spelling);
+    let hyphenation_langs = languages(DataFileType::Hyphenation);
 
     Ok(web::Json(AvailableLanguagesResponse {
         available: AvailableLanguagesByType {
@@ -33,6 +66,7 @@ pub fn get_available_languages_handler(
 }
 
 pub fn get_gramcheck_preferences_handler(
+    req: HttpRequest,
     path: web::Path<String>,
     state: web::Data<State>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
@@ -40,27 +74,56 @@ pub fn get_gramcheck_preferences_handler(
     let language = path;
 
     let lock = prefs.read();
-    let error_tags = match lock.get(&*language) {
-        Some(s) => s,
+    let preferences = match lock.get(&*language) {
+        Some(Ok(preferences)) => preferences,
+        Some(Err(e)) => {
+            return result(Err(ApiError::BackendUnavailable(format!(
+                "Failed to list the grammar checker preferences for language {}: {}",
+                &*language, e
+            ))
+            .into()));
+        }
         None => {
-            return result(Ok(HttpResponse::InternalServerError().into()));
+            return result(Err(ApiError::LanguageNotFound(format!(
+                "No grammar checker available for language {}",
+                &*language
+            ))
+            .into()));
         }
     };
 
+    let ui_language = preferences.negotiate_ui_language(&request_ui_languages(&req));
+
     result(Ok(HttpResponse::Ok().json(GramcheckPreferencesResponse {
-        error_tags: error_tags.to_owned(),
+        error_tags: preferences.error_tags(ui_language.as_deref()),
+        preferences: preferences.clone(),
     })))
 }
 
 pub fn gramchecker_handler(
+    req: HttpRequest,
     body: web::Json<GramcheckRequest>,
     path: web::Path<String>,
     state: web::Data<State>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    let grammar_suggestions = &state.language_functions.grammar_suggestions;
+    let mut request = body.into_inner();
+    let validation = {
+        let preferences = state.gramcheck_preferences.read();
+        request.negotiate_ui_language(&path, &preferences, &request_ui_languages(&req));
+        request.compile_tags(&path, &preferences);
+
+        state
+            .config
+            .limits
+            .check_text("grammar", &request.text)
+            .and_then(|_| request.validate(&path, &preferences))
+    };
 
-    grammar_suggestions
-        .suggestions(body.0, &path)
+    result(validation)
+        .and_then(move |_| {
+            let grammar_suggestions = &state.language_functions.grammar_suggestions;
+            grammar_suggestions.suggestions(request, &path)
+        })
         .from_err()
         .map(|res| HttpResponse::Ok().json(res))
 }
@@ -70,10 +133,13 @@ pub fn hyphenation_handler(
     path: web::Path<String>,
     state: web::Data<State>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    let hyphenation_suggestions = &state.language_functions.hyphenation_suggestions;
+    let validation = state.config.limits.check_text("hyphenation", &body.text);
 
-    hyphenation_suggestions
-        .suggestions(body.0, &path)
+    result(validation)
+        .and_then(move |_| {
+            let hyphenation_suggestions = &state.language_functions.hyphenation_suggestions;
+            hyphenation_suggestions.suggestions(body.into_inner(), &path)
+        })
         .from_err()
         .map(|res| HttpResponse::Ok().json(res))
 }
@@ -83,10 +149,33 @@ pub fn speller_handler(
     path: web::Path<String>,
     state: web::Data<State>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
-    let spelling_suggestions = &state.language_functions.spelling_suggestions;
+    let validation = state.config.limits.check_text("speller", &body.text);
 
-    spelling_suggestions
-        .suggestions(body.0, &path)
+    result(validation)
+        .and_then(move |_| {
+            let spelling_suggestions = &state.language_functions.spelling_suggestions;
+            spelling_suggestions.suggestions(body.into_inner(), &path)
+        })
         .from_err()
         .map(|res| HttpResponse::Ok().json(res))
 }
+
+pub fn batch_handler(
+    req: HttpRequest,
+    body: web::Json<Vec<BatchItem>>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let client = req.extensions().get::<Arc<Client>>().cloned();
+    let client_ip = state
+        .rate_limiter
+        .request_client_ip(req.head().peer_addr, req.headers());
+
+    check_batch(
+        state.get_ref().clone(),
+        client,
+        client_ip,
+        body.into_inner(),
+    )
+    .from_err()
+    .map(|res| HttpResponse::Ok().json(res))
+}
@@ -1,22 +1,44 @@
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
 use std::sync::Arc;
 
 use actix::prelude::*;
-use futures::future::{err, ok, Future};
-use hashbrown::HashMap;
-use log::{info, warn};
+use divvunspell::transducer::hfst::HfstTransducer;
+use divvunspell::transducer::Transducer;
+use divvunspell::types::{FlagDiacriticOperator, SymbolNumber, TransitionTableIndex, Weight};
+use divvunspell::vfs::Fs;
+use futures::future::{err, join_all, ok, Future};
+use hashbrown::{HashMap, HashSet};
+use log::{debug, error, info, warn};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 
 use divvunspell::tokenizer::Tokenize;
 
+use crate::config::{language_settings, Languages};
 use crate::error::ApiError;
+use crate::language::pool::ExecutorPool;
+use crate::metrics::WORDS_PROCESSED;
 use crate::server::state::{LanguageSuggestions, UnhoistFutureExt};
+use crate::server::status::{LanguageStatus, SharedWorkerStatus};
+
+/// Upper bound on the number of states visited for a single word, guarding against epsilon cycles
+const MAX_LOOKUP_STEPS: usize = 100_000;
+
+/// Characters marking hyphenation points in the transducer output
+const HYPHENATION_MARKS: &[char] = &['^', '#'];
+
+pub type Hyphenator = HfstTransducer<File>;
 
 pub struct HyphenationExecutor {
-    pub path: String,
+    pub transducer: Arc<Hyphenator>,
     pub language: String,
+    /// Minimum number of characters before the first hyphenation point
+    pub min_left: usize,
+    /// Minimum number of characters after the last hyphenation point
+    pub min_right: usize,
+    pub status: SharedWorkerStatus,
     pub terminated: bool,
 }
 
@@ -28,6 +50,7 @@ impl actix::Supervised for HyphenationExecutor {
     fn restarting(&mut self, _ctx: &mut Context<HyphenationExecutor>) {
         if !self.terminated {
             warn!("Hyphenation actor for {} died, restarting", &self.language);
+            self.status.write().restarts += 1;
         }
     }
 }
@@ -39,13 +62,20 @@ impl Handler<Die> for HyphenationExecutor {
     type Result = ();
 
     fn handle(&mut self, _: Die, ctx: &mut Context<HyphenationExecutor>) {
+        debug!(
+            "Death message received, stopping hyphenation actor for language `{}`",
+            &self.language
+        );
+
+        self.terminated = true;
+
         // The actor will restart because it's supervised, but if no references remain
         // to the actor it will be dropped
         ctx.stop();
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct HyphenationRequest {
     pub text: String,
 }
@@ -76,69 +106,436 @@ impl Handler<HyphenationRequest> for HyphenationExecutor {
     type Result = Result<HyphenationResponse, ApiError>;
 
     fn handle(&mut self, msg: HyphenationRequest, _: &mut Self::Context) -> Self::Result {
-        let cloned_text = msg.text.clone();
-        let words = cloned_text.word_indices().map(|x| x.1).into_iter();
-
-        let results = words
-            .map(|word| {
-                let mut hfst_child = Command::new("hfst-lookup")
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::piped())
-                    .arg("-n")
-                    .arg("1")
-                    .arg("-q")
-                    .arg(&self.path)
-                    .spawn()?;
-
-                {
-                    let hfst_in = hfst_child.stdin.as_mut().unwrap();
-                    hfst_in.write_all(&word.as_bytes())?;
-                }
-
-                let result = String::from_utf8(hfst_child.wait_with_output()?.stdout)?
-                    .trim()
-                    .to_string();
-
-                let hyphenations = result
-                    .lines()
-                    .map(|line| {
-                        let components: Vec<&str> = line.split("\t").collect();
-                        if components.len() < 3 {
-                            return Err(ApiError {
-                                message: format!("hfst-lookup returning unexpected number of tokens per word: {}", line),
-                            });
-                        }
-
-                        let weight = components[2].parse();
-                        if let Ok(weight) = weight {
-                            return Ok(HyphenationPattern {
-                                value: components[1].to_owned(),
-                                weight,
-                            });
-                        } else {
-                            return Err(ApiError {
-                                message: format!("Failed to parse float value from hfst-lookup: {}", line),
-                            });
-                        }
-                    })
-                    .collect::<Result<Vec<HyphenationPattern>, ApiError>>()?;
+        let words = WORDS_PROCESSED.with_label_values(&["hyphenation", &self.language]);
+
+        let results = msg
+            .text
+            .word_indices()
+            .inspect(|_| words.inc())
+            .map(|(_, word)| {
+                let hyphenation = lookup(self.transducer.as_ref(), word).map_err(|steps| {
+                    ApiError::Internal(format!(
+                        "Hyphenating `{}` for language {} gave up after visiting {} states",
+                        word, &self.language, steps
+                    ))
+                })?;
 
                 Ok(HyphenationResult {
                     word: word.to_owned(),
-                    hyphenations,
+                    hyphenations: restrict_hyphenations(
+                        hyphenation.into_iter().collect(),
+                        self.min_left,
+                        self.min_right,
+                    ),
                 })
             })
-            .collect::<Result<Vec<HyphenationResult>, ApiError>>()?;
+            .collect::<Result<Vec<_>, ApiError>>()?;
 
         Ok(HyphenationResponse {
-            text: cloned_text,
+            text: msg.text,
             results,
         })
     }
 }
 
+/// A flag diacritic operation, as in `@U.CASE.NOM@`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FlagOperation {
+    operator: FlagDiacriticOperator,
+    feature: SymbolNumber,
+    value: i16,
+}
+
+/// The values of the flag diacritic features on a path, 0 where a feature is unset
+#[derive(Debug, Clone, Default)]
+struct FlagState(Vec<i16>);
+
+impl FlagState {
+    fn get(&self, feature: SymbolNumber) -> i16 {
+        self.0.get(feature as usize).cloned().unwrap_or(0)
+    }
+
+    fn set(&mut self, feature: SymbolNumber, value: i16) {
+        let index = feature as usize;
+        if self.0.len() <= index {
+            self.0.resize(index + 1, 0);
+        }
+        self.0[index] = value;
+    }
+
+    /// Applies the operation, returning whether the path may be followed past it
+    fn apply(&mut self, operation: FlagOperation) -> bool {
+        let FlagOperation {
+            operator,
+            feature,
+            value,
+        } = operation;
+        let current = self.get(feature);
+
+        match operator {
+            FlagDiacriticOperator::PositiveSet => {
+                self.set(feature, value);
+                true
+            }
+            FlagDiacriticOperator::NegativeSet => {
+                self.set(feature, -value);
+                true
+            }
+            FlagDiacriticOperator::Require if value == 0 => current != 0,
+            FlagDiacriticOperator::Require => current == value,
+            FlagDiacriticOperator::Disallow if value == 0 => current == 0,
+            FlagDiacriticOperator::Disallow => current != value,
+            FlagDiacriticOperator::Clear => {
+                self.set(feature, 0);
+                true
+            }
+            FlagDiacriticOperator::Unification => {
+                if current == 0 || current == value || (current < 0 && -current != value) {
+                    self.set(feature, value);
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+/// A transition as seen by the lookup, with the symbol it writes
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    symbol: SymbolNumber,
+    target: TransitionTableIndex,
+    weight: Weight,
+}
+
+/// The parts of a transducer walked by `lookup`, kept apart from the HFST format so that the
+/// lookup can be tested against a small fixture
+trait LookupFst {
+    /// The input symbol spelled by the string, leaving out epsilons and flag diacritics
+    fn input_symbol(&self, string: &str) -> Option<SymbolNumber>;
+    /// Length in characters of the longest symbol
+    fn longest_symbol(&self) -> usize;
+    /// The text written by the symbol, nothing for epsilons and flag diacritics
+    fn output_string(&self, symbol: SymbolNumber) -> Option<&str>;
+    fn flag_operation(&self, symbol: SymbolNumber) -> Option<FlagOperation>;
+    /// The final weight of the state, if it is final
+    fn final_weight_of(&self, state: TransitionTableIndex) -> Option<Weight>;
+    /// The transitions that consume no input: epsilons and flag diacritics
+    fn epsilon_edges(&self, state: TransitionTableIndex) -> Vec<Edge>;
+    fn edges(&self, state: TransitionTableIndex, symbol: SymbolNumber) -> Vec<Edge>;
+}
+
+impl LookupFst for Hyphenator {
+    fn input_symbol(&self, string: &str) -> Option<SymbolNumber> {
+        let alphabet = self.alphabet();
+
+        alphabet
+            .string_to_symbol()
+            .get(string)
+            .cloned()
+            .filter(|&symbol| symbol != 0 && !alphabet.is_flag(symbol))
+    }
+
+    fn longest_symbol(&self) -> usize {
+        self.alphabet()
+            .key_table()
+            .iter()
+            .map(|key| key.as_str().chars().count())
+            .max()
+            .unwrap_or(1)
+    }
+
+    fn output_string(&self, symbol: SymbolNumber) -> Option<&str> {
+        let alphabet = self.alphabet();
+
+        if symbol == 0 || alphabet.is_flag(symbol) {
+            return None;
+        }
+
+        alphabet
+            .key_table()
+            .get(symbol as usize)
+            .map(|key| key.as_str())
+    }
+
+    fn flag_operation(&self, symbol: SymbolNumber) -> Option<FlagOperation> {
+        self.alphabet()
+            .operations()
+            .get(&symbol)
+            .map(|operation| FlagOperation {
+                operator: operation.operation,
+                feature: operation.feature,
+                value: operation.value,
+            })
+    }
+
+    fn final_weight_of(&self, state: TransitionTableIndex) -> Option<Weight> {
+        if self.is_final(state) {
+            Some(self.final_weight(state).unwrap_or(0.0))
+        } else {
+            None
+        }
+    }
+
+    fn epsilon_edges(&self, state: TransitionTableIndex) -> Vec<Edge> {
+        let next_state = state + 1;
+        let mut edges = vec![];
+
+        if self.has_epsilons_or_flags(next_state) {
+            if let Some(mut next) = self.next(next_state, 0) {
+                while let Some(transition) = self.take_epsilons_and_flags(next) {
+                    if let Some(target) = transition.target() {
+                        edges.push(Edge {
+                            symbol: transition.symbol().unwrap_or(0),
+                            target,
+                            weight: transition.weight().unwrap_or(0.0),
+                        });
+                    }
+                    next += 1;
+                }
+            }
+        }
+
+        edges
+    }
+
+    fn edges(&self, state: TransitionTableIndex, symbol: SymbolNumber) -> Vec<Edge> {
+        let next_state = state + 1;
+        let mut edges = vec![];
+
+        if self.has_transitions(next_state, Some(symbol)) {
+            if let Some(mut next) = self.next(next_state, symbol) {
+                while let Some(transition) = self.take_non_epsilons(next, symbol) {
+                    if let Some(target) = transition.target() {
+                        edges.push(Edge {
+                            symbol: transition.symbol().unwrap_or(0),
+                            target,
+                            weight: transition.weight().unwrap_or(0.0),
+                        });
+                    }
+                    next += 1;
+                }
+            }
+        }
+
+        edges
+    }
+}
+
+/// Splits the word into input symbols, preferring the longest symbol at every position like
+/// `hfst-lookup` does
+fn tokenize<F: LookupFst>(fst: &F, word: &str) -> Option<Vec<SymbolNumber>> {
+    let chars: Vec<(usize, char)> = word.char_indices().collect();
+    let longest = fst.longest_symbol().max(1);
+    let mut symbols = vec![];
+    let mut position = 0;
+
+    while position < chars.len() {
+        let start = chars[position].0;
+
+        let (length, symbol) =
+            (1..=longest.min(chars.len() - position))
+                .rev()
+                .find_map(|length| {
+                    let end = chars
+                        .get(position + length)
+                        .map_or(word.len(), |(index, _)| *index);
+                    fst.input_symbol(&word[start..end])
+                        .map(|symbol| (length, symbol))
+                })?;
+
+        symbols.push(symbol);
+        position += length;
+    }
+
+    Some(symbols)
+}
+
+enum Entry {
+    /// A state reached after consuming part of the input
+    State {
+        index: TransitionTableIndex,
+        input_position: usize,
+        flags: FlagState,
+    },
+    /// A complete path, whose weight includes the final weight
+    Result,
+}
+
+struct Candidate {
+    weight: Weight,
+    output: String,
+    entry: Entry,
+}
+
+// The heap pops the lightest candidate first
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .weight
+            .partial_cmp(&self.weight)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+/// Looks the word up in the hyphenation transducer, returning its lightest hyphenation like
+/// `hfst-lookup -n 1` did. Paths whose flag diacritics fail are not followed. If a lookup visits
+/// more than `MAX_LOOKUP_STEPS` states, it gives up and returns the number of states visited.
+fn lookup<F: LookupFst>(fst: &F, word: &str) -> Result<Option<HyphenationPattern>, usize> {
+    // Words with characters unknown to the transducer cannot be hyphenated
+    let input = match tokenize(fst, word) {
+        Some(input) => input,
+        None => return Ok(None),
+    };
+
+    let mut heap = BinaryHeap::new();
+    heap.push(Candidate {
+        weight: 0.0,
+        output: String::new(),
+        entry: Entry::State {
+            index: 0,
+            input_position: 0,
+            flags: FlagState::default(),
+        },
+    });
+    let mut steps = 0;
+
+    while let Some(Candidate {
+        weight,
+        output,
+        entry,
+    }) = heap.pop()
+    {
+        let (index, input_position, flags) = match entry {
+            Entry::Result => {
+                return Ok(Some(HyphenationPattern {
+                    value: output,
+                    weight: f64::from(weight),
+                }));
+            }
+            Entry::State {
+                index,
+                input_position,
+                flags,
+            } => (index, input_position, flags),
+        };
+
+        steps += 1;
+        if steps > MAX_LOOKUP_STEPS {
+            return Err(steps);
+        }
+
+        if input_position == input.len() {
+            if let Some(final_weight) = fst.final_weight_of(index) {
+                heap.push(Candidate {
+                    weight: weight + final_weight,
+                    output: output.clone(),
+                    entry: Entry::Result,
+                });
+            }
+        }
+
+        let follow = |edge: Edge, consumed: usize, flags: FlagState| {
+            let mut next_output = output.clone();
+            if let Some(string) = fst.output_string(edge.symbol) {
+                next_output.push_str(string);
+            }
+
+            Candidate {
+                weight: weight + edge.weight,
+                output: next_output,
+                entry: Entry::State {
+                    index: edge.target,
+                    input_position: input_position + consumed,
+                    flags,
+                },
+            }
+        };
+
+        for edge in fst.epsilon_edges(index) {
+            let mut next_flags = flags.clone();
+            if let Some(operation) = fst.flag_operation(edge.symbol) {
+                if !next_flags.apply(operation) {
+                    continue;
+                }
+            }
+
+            heap.push(follow(edge, 0, next_flags));
+        }
+
+        if let Some(&symbol) = input.get(input_position) {
+            for edge in fst.edges(index, symbol) {
+                heap.push(follow(edge, 1, flags.clone()));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Drops the hyphenation points too close to the start or end of the word, along with the
+/// patterns that become duplicates of lighter ones by it
+fn restrict_hyphenations(
+    patterns: Vec<HyphenationPattern>,
+    min_left: usize,
+    min_right: usize,
+) -> Vec<HyphenationPattern> {
+    if min_left == 0 && min_right == 0 {
+        return patterns;
+    }
+
+    let mut seen = HashSet::new();
+
+    patterns
+        .into_iter()
+        .map(|pattern| {
+            let length = pattern
+                .value
+                .chars()
+                .filter(|c| !HYPHENATION_MARKS.contains(c))
+                .count();
+            let mut position = 0;
+            let mut value = String::with_capacity(pattern.value.len());
+
+            for c in pattern.value.chars() {
+                if HYPHENATION_MARKS.contains(&c) {
+                    if position >= min_left && length - position >= min_right {
+                        value.push(c);
+                    }
+                } else {
+                    position += 1;
+                    value.push(c);
+                }
+            }
+
+            HyphenationPattern {
+                value,
+                weight: pattern.weight,
+            }
+        })
+        .filter(|pattern| seen.insert(pattern.value.clone()))
+        .collect()
+}
+
 pub struct AsyncHyphenator {
-    pub hyphenators: Arc<RwLock<HashMap<String, Addr<HyphenationExecutor>>>>,
+    pub hyphenators: Arc<RwLock<HashMap<String, ExecutorPool<HyphenationExecutor>>>>,
+    pub pool_size: usize,
+    pub languages: Languages,
 }
 
 impl LanguageSuggestions for AsyncHyphenator {
@@ -153,11 +550,12 @@ impl LanguageSuggestions for AsyncHyphenator {
         let lock = self.hyphenators.read();
 
         let hyphenator = match lock.get(language) {
-            Some(s) => s,
+            Some(s) => s.next(),
             None => {
-                return Box::new(err(ApiError {
-                    message: format!("No hyphenator available for language {}", &language),
-                }));
+                return Box::new(err(ApiError::LanguageNotFound(format!(
+                    "No hyphenator available for language {}",
+                    &language
+                ))));
             }
         };
 
@@ -166,11 +564,11 @@ impl LanguageSuggestions for AsyncHyphenator {
         Box::new(
             hyphenator
                 .send(message)
-                .map_err(move |err| ApiError {
-                    message: format!(
+                .map_err(move |err| {
+                    ApiError::BackendUnavailable(format!(
                         "Something failed in the message delivery process for language {}: {}",
                         &language, err
-                    ),
+                    ))
                 })
                 .unhoist(),
         )
@@ -179,18 +577,35 @@ impl LanguageSuggestions for AsyncHyphenator {
     fn add(&self, language: &str, path: &str) -> Box<dyn Future<Item = (), Error = ApiError>> {
         info!("Adding Hyphenator for {}", language);
 
+        let transducer = match Hyphenator::from_path(&Fs, path) {
+            Ok(transducer) => Arc::new(transducer),
+            Err(e) => {
+                let message = format!(
+                    "Failed to load hyphenator for language {}: {:?}",
+                    &language, e
+                );
+                error!("{}", &message);
+
+                return Box::new(err(ApiError::BackendUnavailable(message)));
+            }
+        };
+
         let mut lock = self.hyphenators.write();
 
-        let hyphenator_path = path.to_owned();
+        let settings = language_settings(&self.languages, language);
+        let min_left = settings.hyphenation_min_left.unwrap_or(0);
+        let min_right = settings.hyphenation_min_right.unwrap_or(0);
 
         let owned_language = language.to_owned();
-        let hyphenator = actix::Supervisor::start_in_arbiter(&actix::Arbiter::new(), move |_| {
-            HyphenationExecutor {
-                path: hyphenator_path,
-                language: owned_language,
+        let hyphenator =
+            ExecutorPool::start(self.pool_size, path, move |status| HyphenationExecutor {
+                transducer: Arc::clone(&transducer),
+                language: owned_language.clone(),
+                min_left,
+                min_right,
+                status,
                 terminated: false,
-            }
-        });
+            });
 
         lock.insert(language.to_owned(), hyphenator);
 
@@ -205,31 +620,298 @@ impl LanguageSuggestions for AsyncHyphenator {
         let hyphenator = match lock.remove(language) {
             Some(s) => s,
             None => {
-                return Box::new(err(ApiError {
-                    message: format!("No hyphenator available for language {}", &language),
-                }));
+                return Box::new(err(ApiError::LanguageNotFound(format!(
+                    "No hyphenator available for language {}",
+                    &language
+                ))));
             }
         };
 
         let cloned_hyphenators = Arc::clone(&self.hyphenators);
         let language = language.to_owned();
 
-        Box::new(
+        let deaths = join_all(
             hyphenator
-                .send(Die)
-                .map_err(move |err| {
-                    // Put the address back in since we failed to send the die message
-                    let mut lock = cloned_hyphenators.write();
-                    lock.insert(language.clone(), hyphenator);
-
-                    ApiError {
-                        message: format!(
-                            "Something failed in the message delivery process for language {}: {}",
-                            &language, err
-                        ),
-                    }
+                .executors()
+                .iter()
+                .map(|executor| executor.send(Die))
+                .collect::<Vec<_>>(),
+        );
+
+        Box::new(deaths.then(move |result| match result {
+            Ok(_) => {
+                // The executors are gone, so the threads they ran on can go too
+                hyphenator.stop();
+                Ok(())
+            }
+            Err(err) => {
+                // Put the pool back in since we failed to send the die message
+                let mut lock = cloned_hyphenators.write();
+                lock.insert(language.clone(), hyphenator);
+
+                Err(ApiError::BackendUnavailable(format!(
+                    "Something failed in the message delivery process for language {}: {}",
+                    &language, err
+                )))
+            }
+        }))
+    }
+
+    fn has_language(&self, language: &str) -> bool {
+        self.hyphenators.read().contains_key(language)
+    }
+
+    fn status(&self) -> Vec<LanguageStatus> {
+        let lock = self.hyphenators.read();
+
+        lock.iter()
+            .map(|(language, pool)| pool.status(language))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A transducer kept as a list of arcs, with symbol 0 as epsilon
+    struct Fixture {
+        symbols: Vec<&'static str>,
+        flags: HashMap<SymbolNumber, FlagOperation>,
+        finals: HashMap<TransitionTableIndex, Weight>,
+        /// (source, input, output, target, weight)
+        arcs: Vec<(
+            TransitionTableIndex,
+            SymbolNumber,
+            SymbolNumber,
+            TransitionTableIndex,
+            Weight,
+        )>,
+    }
+
+    impl Fixture {
+        fn new(symbols: &[&'static str]) -> Fixture {
+            let mut all = vec![""];
+            all.extend_from_slice(symbols);
+
+            Fixture {
+                symbols: all,
+                flags: HashMap::new(),
+                finals: HashMap::new(),
+                arcs: vec![],
+            }
+        }
+
+        fn symbol(&self, string: &str) -> SymbolNumber {
+            self.symbols.iter().position(|s| *s == string).unwrap() as SymbolNumber
+        }
+
+        fn flag(mut self, string: &'static str, operation: FlagOperation) -> Fixture {
+            self.symbols.push(string);
+            self.flags
+                .insert((self.symbols.len() - 1) as SymbolNumber, operation);
+            self
+        }
+
+        fn arc(
+            mut self,
+            source: TransitionTableIndex,
+            input: &str,
+            output: &str,
+            target: TransitionTableIndex,
+            weight: Weight,
+        ) -> Fixture {
+            let input = self.symbol(input);
+            let output = self.symbol(output);
+            self.arcs.push((source, input, output, target, weight));
+            self
+        }
+
+        fn final_state(mut self, state: TransitionTableIndex, weight: Weight) -> Fixture {
+            self.finals.insert(state, weight);
+            self
+        }
+
+        fn edges_where<P: Fn(SymbolNumber) -> bool>(
+            &self,
+            state: TransitionTableIndex,
+            input: P,
+        ) -> Vec<Edge> {
+            self.arcs
+                .iter()
+                .filter(|arc| arc.0 == state && input(arc.1))
+                .map(|arc| Edge {
+                    symbol: arc.2,
+                    target: arc.3,
+                    weight: arc.4,
                 })
-                .and_then(|_| ok(())),
-        )
+                .collect()
+        }
+    }
+
+    impl LookupFst for Fixture {
+        fn input_symbol(&self, string: &str) -> Option<SymbolNumber> {
+            self.symbols
+                .iter()
+                .position(|s| *s == string)
+                .map(|symbol| symbol as SymbolNumber)
+                .filter(|symbol| *symbol != 0 && !self.flags.contains_key(symbol))
+        }
+
+        fn longest_symbol(&self) -> usize {
+            self.symbols
+                .iter()
+                .map(|s| s.chars().count())
+                .max()
+                .unwrap_or(1)
+        }
+
+        fn output_string(&self, symbol: SymbolNumber) -> Option<&str> {
+            if symbol == 0 || self.flags.contains_key(&symbol) {
+                return None;
+            }
+
+            self.symbols.get(symbol as usize).cloned()
+        }
+
+        fn flag_operation(&self, symbol: SymbolNumber) -> Option<FlagOperation> {
+            self.flags.get(&symbol).cloned()
+        }
+
+        fn final_weight_of(&self, state: TransitionTableIndex) -> Option<Weight> {
+            self.finals.get(&state).cloned()
+        }
+
+        fn epsilon_edges(&self, state: TransitionTableIndex) -> Vec<Edge> {
+            self.edges_where(state, |input| input == 0 || self.flags.contains_key(&input))
+        }
+
+        fn edges(&self, state: TransitionTableIndex, symbol: SymbolNumber) -> Vec<Edge> {
+            self.edges_where(state, |input| input == symbol)
+        }
+    }
+
+    fn flag(operator: FlagDiacriticOperator, value: i16) -> FlagOperation {
+        FlagOperation {
+            operator,
+            feature: 1,
+            value,
+        }
+    }
+
+    fn pattern(value: &str, weight: f64) -> HyphenationPattern {
+        HyphenationPattern {
+            value: value.to_owned(),
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_restrict_hyphenations() {
+        let patterns = vec![
+            pattern("o^đas^maht^tin#mi^nist^tar", 60.0),
+            pattern("o^đas^maht^tin^mi^nist^tar", 5000.0),
+        ];
+
+        let values: Vec<String> = restrict_hyphenations(patterns.clone(), 0, 0)
+            .into_iter()
+            .map(|pattern| pattern.value)
+            .collect();
+        assert_eq!(
+            values,
+            vec!["o^đas^maht^tin#mi^nist^tar", "o^đas^maht^tin^mi^nist^tar"]
+        );
+
+        let restricted = restrict_hyphenations(patterns, 2, 4);
+        assert_eq!(restricted.len(), 2);
+        assert_eq!(restricted[0].value, "ođas^maht^tin#mi^nisttar");
+        assert_eq!(restricted[1].value, "ođas^maht^tin^mi^nisttar");
+
+        let collapsed = restrict_hyphenations(
+            vec![pattern("pák^hat", 10.0), pattern("pá^khat", 20.0)],
+            4,
+            0,
+        );
+        assert_eq!(collapsed.len(), 1);
+        assert_eq!(collapsed[0].value, "pákhat");
+        assert!((collapsed[0].weight - 10.0).abs() < std::f64::EPSILON);
+    }
+
+    #[test]
+    fn test_lookup_lightest_path() {
+        // ka^la with weight 1, or kal^a with weight 3
+        let fst = Fixture::new(&["k", "a", "l", "^"])
+            .arc(0, "k", "k", 1, 0.0)
+            .arc(1, "a", "a", 2, 0.0)
+            .arc(2, "", "^", 3, 1.0)
+            .arc(3, "l", "l", 4, 0.0)
+            .arc(2, "l", "l", 5, 0.0)
+            .arc(5, "", "^", 6, 2.0)
+            .arc(6, "", "", 4, 1.0)
+            .arc(4, "a", "a", 7, 0.0)
+            .final_state(7, 0.5);
+
+        let result = lookup(&fst, "kala").unwrap().unwrap();
+        assert_eq!(result.value, "ka^la");
+        assert!((result.weight - 1.5).abs() < std::f64::EPSILON);
+
+        assert!(lookup(&fst, "kal").unwrap().is_none());
+        assert!(lookup(&fst, "kaxa").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_lookup_multichar_symbols() {
+        let fst = Fixture::new(&["a", "l", "ll", "^"])
+            .arc(0, "a", "a", 1, 0.0)
+            .arc(1, "", "^", 2, 0.0)
+            .arc(2, "ll", "ll", 3, 0.0)
+            .arc(3, "a", "a", 4, 0.0)
+            .final_state(4, 0.0);
+
+        assert_eq!(lookup(&fst, "alla").unwrap().unwrap().value, "a^lla");
+    }
+
+    #[test]
+    fn test_lookup_flag_diacritics() {
+        // The lighter path sets the feature to 1 and then requires 2, so it must be rejected
+        let fst = Fixture::new(&["a", "b", "^"])
+            .flag("@P.X.ONE@", flag(FlagDiacriticOperator::PositiveSet, 1))
+            .flag("@R.X.TWO@", flag(FlagDiacriticOperator::Require, 2))
+            .flag("@P.X.TWO@", flag(FlagDiacriticOperator::PositiveSet, 2));
+        let fst = fst
+            .arc(0, "@P.X.ONE@", "@P.X.ONE@", 1, 0.0)
+            .arc(0, "@P.X.TWO@", "@P.X.TWO@", 1, 5.0)
+            .arc(1, "a", "a", 2, 0.0)
+            .arc(2, "", "^", 3, 0.0)
+            .arc(3, "b", "b", 4, 0.0)
+            .arc(4, "@R.X.TWO@", "@R.X.TWO@", 5, 0.0)
+            .final_state(5, 0.0);
+
+        let result = lookup(&fst, "ab").unwrap().unwrap();
+        assert_eq!(result.value, "a^b");
+        assert!((result.weight - 5.0).abs() < std::f64::EPSILON);
+    }
+
+    #[test]
+    fn test_flag_state() {
+        let mut state = FlagState::default();
+        assert!(state.apply(flag(FlagDiacriticOperator::Disallow, 0)));
+        assert!(!state.apply(flag(FlagDiacriticOperator::Require, 0)));
+        assert!(state.apply(flag(FlagDiacriticOperator::Unification, 3)));
+        assert!(!state.apply(flag(FlagDiacriticOperator::Unification, 4)));
+        assert!(state.apply(flag(FlagDiacriticOperator::NegativeSet, 3)));
+        assert!(state.apply(flag(FlagDiacriticOperator::Unification, 4)));
+        assert!(state.apply(flag(FlagDiacriticOperator::Require, 4)));
+        assert!(state.apply(flag(FlagDiacriticOperator::Clear, 0)));
+        assert!(!state.apply(flag(FlagDiacriticOperator::Require, 0)));
+    }
+
+    #[test]
+    fn test_lookup_step_limit() {
+        let fst = Fixture::new(&["a"])
+            .arc(0, "a", "a", 1, 0.0)
+            .arc(1, "", "", 1, 0.0);
+
+        assert!(lookup(&fst, "a").is_err());
     }
 }
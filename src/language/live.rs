@@ -0,0 +1,376 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use hashbrown::HashMap;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::ApiError;
+use crate::server::auth::Client;
+use crate::server::state::State;
+
+use super::batch::{check_item, BatchError, BatchItem, BatchItemResult, BatchService};
+use super::grammar::GramcheckResponse;
+use super::speller::SpellerResponse;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Sessions that haven't answered a ping for this long are closed
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sessions that haven't sent the API key needed for this long are closed
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most paragraphs of a session being checked at the same time
+const MAX_IN_FLIGHT_PARAGRAPHS: usize = 32;
+
+/// Messages sent by the client
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveRequest {
+    /// The API key, for clients that can't set headers on a WebSocket such as browsers
+    Auth { key: String },
+    /// The paragraph's text changed, the previous check of the paragraph is no longer wanted
+    Edit { paragraph: String, text: String },
+    /// The paragraph was deleted, its check is no longer wanted
+    Remove { paragraph: String },
+}
+
+/// Messages pushed to the client
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveResponse {
+    Diagnostics {
+        paragraph: String,
+        speller: Option<SpellerResponse>,
+        grammar: Option<GramcheckResponse>,
+        /// Errors of the services that failed, the other services' results are still returned
+        errors: Vec<BatchError>,
+    },
+    Error {
+        /// The paragraph that couldn't be checked, if the error is about one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        paragraph: Option<String>,
+        code: String,
+        message: String,
+    },
+}
+
+impl From<BatchItemResult> for LiveResponse {
+    fn from(result: BatchItemResult) -> Self {
+        LiveResponse::Diagnostics {
+            paragraph: result.id,
+            speller: result.speller,
+            grammar: result.grammar,
+            errors: result.errors,
+        }
+    }
+}
+
+impl From<ApiError> for LiveResponse {
+    fn from(error: ApiError) -> Self {
+        LiveResponse::Error {
+            paragraph: None,
+            code: error.code().to_owned(),
+            message: error.message().to_owned(),
+        }
+    }
+}
+
+impl LiveResponse {
+    fn paragraph_error(paragraph: String, error: ApiError) -> Self {
+        LiveResponse::Error {
+            paragraph: Some(paragraph),
+            code: error.code().to_owned(),
+            message: error.message().to_owned(),
+        }
+    }
+}
+
+/// A live checking session for one language. Every edit of a paragraph cancels the check of its
+/// previous text; checks still queued for an executor are then skipped, as nobody waits for them.
+/// Every edit counts as a request against the limits of the client's IP address and API key.
+pub struct LiveSession {
+    state: State,
+    client: Option<Arc<Client>>,
+    /// Whether the session may check paragraphs, false until the client sends its API key if
+    /// one is needed
+    authenticated: bool,
+    client_ip: Option<IpAddr>,
+    language: String,
+    services: Vec<BatchService>,
+    in_flight: HashMap<String, SpawnHandle>,
+    heartbeat: Instant,
+}
+
+impl LiveSession {
+    pub fn new(
+        state: State,
+        client: Option<Arc<Client>>,
+        client_ip: Option<IpAddr>,
+        language: String,
+        services: Vec<BatchService>,
+    ) -> Self {
+        let authenticated = client.is_some() || !state.authenticator.enabled();
+
+        LiveSession {
+            state,
+            client,
+            authenticated,
+            client_ip,
+            language,
+            services,
+            in_flight: HashMap::new(),
+            heartbeat: Instant::now(),
+        }
+    }
+
+    fn send(&self, response: &LiveResponse, ctx: &mut ws::WebsocketContext<Self>) {
+        match serde_json::to_string(response) {
+            Ok(text) => ctx.text(text),
+            Err(e) => warn!("Failed to serialize live response: {}", e),
+        }
+    }
+
+    fn cancel(&mut self, paragraph: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(handle) = self.in_flight.remove(paragraph) {
+            debug!("Cancelling stale check of paragraph {}", paragraph);
+            ctx.cancel_future(handle);
+        }
+    }
+
+    fn authenticate(&mut self, key: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.authenticated {
+            let error = ApiError::InvalidInput("The session is already authenticated".to_owned());
+            self.send(&error.into(), ctx);
+            return;
+        }
+
+        let authenticator = &self.state.authenticator;
+        // Counted like a session opened with the key in a header
+        let result = authenticator.authenticate(Some(key)).and_then(|client| {
+            if let Some(client) = &client {
+                authenticator.count(client)?;
+            }
+            Ok(client)
+        });
+
+        match result {
+            Ok(client) => {
+                self.client = client;
+                self.authenticated = true;
+            }
+            Err(e) => {
+                self.send(&e.into(), ctx);
+                ctx.stop();
+            }
+        }
+    }
+
+    /// Checks whether another paragraph may be checked, counting it against the client's limits
+    fn allow_check(&self) -> Result<(), ApiError> {
+        if !self.authenticated {
+            return Err(ApiError::Unauthorized(
+                "An API key is required, send it in an auth message first".to_owned(),
+            ));
+        }
+
+        if self.in_flight.len() >= MAX_IN_FLIGHT_PARAGRAPHS {
+            return Err(ApiError::TooManyRequests(
+                format!(
+                    "At most {} paragraphs can be checked at the same time",
+                    MAX_IN_FLIGHT_PARAGRAPHS
+                ),
+                None,
+            ));
+        }
+
+        // The API key's limits are counted by every service that checks the paragraph
+        match self.client_ip {
+            Some(client_ip) => self.state.rate_limiter.check(client_ip, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    fn check(&mut self, paragraph: String, text: String, ctx: &mut ws::WebsocketContext<Self>) {
+        self.cancel(&paragraph, ctx);
+
+        if let Err(e) = self.allow_check() {
+            self.send(&LiveResponse::paragraph_error(paragraph, e), ctx);
+            return;
+        }
+
+        let item = BatchItem {
+            id: paragraph.clone(),
+            language: self.language.clone(),
+            services: self.services.clone(),
+            text,
+        };
+
+        let id = paragraph.clone();
+        let check = check_item(&self.state, &self.client, item)
+            .into_actor(self)
+            .then(move |result, session, ctx| {
+                session.in_flight.remove(&id);
+
+                let response = match result {
+                    Ok(result) => LiveResponse::from(result),
+                    Err(e) => LiveResponse::paragraph_error(id, e),
+                };
+                session.send(&response, ctx);
+                actix::fut::ok(())
+            });
+
+        let handle = ctx.spawn(check);
+        self.in_flight.insert(paragraph, handle);
+    }
+
+    fn handle_text(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        match serde_json::from_str(text) {
+            Ok(LiveRequest::Auth { key }) => self.authenticate(&key, ctx),
+            Ok(LiveRequest::Edit { paragraph, text }) => self.check(paragraph, text, ctx),
+            Ok(LiveRequest::Remove { paragraph }) => self.cancel(&paragraph, ctx),
+            Err(e) => {
+                let error = ApiError::InvalidInput(format!("Invalid message: {}", e));
+                self.send(&error.into(), ctx);
+            }
+        }
+    }
+
+    fn start_heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |session, ctx| {
+            if session.heartbeat.elapsed() > CLIENT_TIMEOUT {
+                debug!("Live session timed out");
+                ctx.stop();
+                return;
+            }
+
+            ctx.ping("");
+        });
+    }
+}
+
+impl Actor for LiveSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.start_heartbeat(ctx);
+
+        if !self.authenticated {
+            ctx.run_later(AUTH_TIMEOUT, |session, ctx| {
+                if !session.authenticated {
+                    let error = ApiError::Unauthorized("No API key was sent in time".to_owned());
+                    session.send(&error.into(), ctx);
+                    ctx.stop();
+                }
+            });
+        }
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for LiveSession {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        match msg {
+            ws::Message::Text(text) => {
+                self.heartbeat = Instant::now();
+                self.handle_text(&text, ctx);
+            }
+            ws::Message::Ping(msg) => {
+                self.heartbeat = Instant::now();
+                ctx.pong(&msg);
+            }
+            ws::Message::Pong(_) => self.heartbeat = Instant::now(),
+            ws::Message::Binary(_) => {
+                let error = ApiError::InvalidInput("Messages must be JSON text".to_owned());
+                self.send(&error.into(), ctx);
+            }
+            ws::Message::Close(_) => ctx.stop(),
+            ws::Message::Nop => (),
+        }
+    }
+}
+
+/// Opens a live checking session for the language, running the speller and grammar checker if
+/// they are available for it
+pub fn live_handler(
+    req: HttpRequest,
+    stream: web::Payload,
+    path: web::Path<String>,
+    state: web::Data<State>,
+) -> actix_web::Result<HttpResponse> {
+    let language = path.into_inner();
+    let language_functions = &state.language_functions;
+
+    let mut services = vec![];
+    if language_functions
+        .spelling_suggestions
+        .has_language(&language)
+    {
+        services.push(BatchService::Speller);
+    }
+    if language_functions
+        .grammar_suggestions
+        .has_language(&language)
+    {
+        services.push(BatchService::Grammar);
+    }
+
+    if services.is_empty() {
+        return Err(ApiError::LanguageNotFound(format!(
+            "No speller or grammar checker available for language {}",
+            language
+        ))
+        .into());
+    }
+
+    let client = req.extensions().get::<Arc<Client>>().cloned();
+    let client_ip = state
+        .rate_limiter
+        .request_client_ip(req.head().peer_addr, req.headers());
+    let session = LiveSession::new(
+        state.get_ref().clone(),
+        client,
+        client_ip,
+        language,
+        services,
+    );
+
+    ws::start(session, &req, stream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_live_request() {
+        assert_eq!(
+            serde_json::from_str::<LiveRequest>(
+                r#"{"type": "edit", "paragraph": "p1", "text": "Sámegiella"}"#
+            )
+            .unwrap(),
+            LiveRequest::Edit {
+                paragraph: "p1".to_owned(),
+                text: "Sámegiella".to_owned(),
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<LiveRequest>(r#"{"type": "remove", "paragraph": "p1"}"#)
+                .unwrap(),
+            LiveRequest::Remove {
+                paragraph: "p1".to_owned(),
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<LiveRequest>(r#"{"type": "auth", "key": "secret"}"#).unwrap(),
+            LiveRequest::Auth {
+                key: "secret".to_owned(),
+            }
+        );
+        assert!(serde_json::from_str::<LiveRequest>(r#"{"type": "edit", "text": ""}"#).is_err());
+    }
+}
@@ -1,5 +1,9 @@
+pub mod batch;
 pub mod data_files;
 pub mod grammar;
 pub mod handlers;
 pub mod hyphenation;
+pub mod live;
+pub mod pool;
+pub mod preferences;
 pub mod speller;
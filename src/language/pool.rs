@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use actix::prelude::*;
+use parking_lot::RwLock;
+
+use crate::server::status::{unix_seconds, LanguageStatus, SharedWorkerStatus, WorkerStatus};
+
+/// A fixed number of supervised executors for a single language data file, each running in its
+/// own arbiter
+pub struct ExecutorPool<A>
+where
+    A: Actor<Context = Context<A>> + Supervised,
+{
+    executors: Vec<Addr<A>>,
+    /// The arbiter of every executor, each running on a thread of its own
+    arbiters: Vec<Arbiter>,
+    statuses: Vec<SharedWorkerStatus>,
+    next: AtomicUsize,
+    path: String,
+    loaded_at: SystemTime,
+}
+
+impl<A> ExecutorPool<A>
+where
+    A: Actor<Context = Context<A>> + Supervised,
+{
+    /// Starts `size` executors, passing each the status it should keep up to date
+    pub fn start<F>(size: usize, path: &str, factory: F) -> Self
+    where
+        F: Fn(SharedWorkerStatus) -> A + Clone + Send + 'static,
+    {
+        // A pool without executors would have nothing to dispatch to
+        let size = size.max(1);
+
+        let statuses: Vec<SharedWorkerStatus> = (0..size)
+            .map(|_| Arc::new(RwLock::new(WorkerStatus::default())))
+            .collect();
+
+        let arbiters: Vec<Arbiter> = (0..size).map(|_| Arbiter::new()).collect();
+
+        let executors = statuses
+            .iter()
+            .zip(&arbiters)
+            .map(|(status, arbiter)| {
+                let factory = factory.clone();
+                let status = Arc::clone(status);
+                Supervisor::start_in_arbiter(arbiter, move |_| factory(status))
+            })
+            .collect();
+
+        ExecutorPool {
+            executors,
+            arbiters,
+            statuses,
+            next: AtomicUsize::new(0),
+            path: path.to_owned(),
+            loaded_at: SystemTime::now(),
+        }
+    }
+
+    /// Returns the next executor in round-robin order
+    pub fn next(&self) -> &Addr<A> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.executors.len();
+        &self.executors[index]
+    }
+
+    pub fn executors(&self) -> &[Addr<A>] {
+        &self.executors
+    }
+
+    /// Stops the arbiters of the executors, ending their threads. Messages the executors haven't
+    /// handled yet are dropped, so they should be told to die and have answered first.
+    pub fn stop(&self) {
+        for arbiter in &self.arbiters {
+            arbiter.stop();
+        }
+    }
+
+    pub fn status(&self, language: &str) -> LanguageStatus {
+        LanguageStatus {
+            language: language.to_owned(),
+            pipeline: None,
+            ui_language: None,
+            path: self.path.clone(),
+            loaded_at: unix_seconds(self.loaded_at),
+            workers: self
+                .statuses
+                .iter()
+                .map(|status| status.read().clone())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::{self, join_all};
+
+    use super::*;
+
+    /// Counts the messages it handles in the restarts of its status
+    struct Counter {
+        status: SharedWorkerStatus,
+    }
+
+    impl Actor for Counter {
+        type Context = Context<Self>;
+    }
+
+    impl Supervised for Counter {}
+
+    struct Count;
+
+    impl Message for Count {
+        type Result = ();
+    }
+
+    impl Handler<Count> for Counter {
+        type Result = ();
+
+        fn handle(&mut self, _: Count, _: &mut Self::Context) -> Self::Result {
+            self.status.write().restarts += 1;
+        }
+    }
+
+    fn counts(pool: &ExecutorPool<Counter>) -> Vec<u32> {
+        pool.status("se")
+            .workers
+            .iter()
+            .map(|worker| worker.restarts)
+            .collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut system = System::new("test");
+        let pool = system
+            .block_on(future::lazy(|| {
+                Ok::<_, ()>(ExecutorPool::start(3, "se.zcheck", |status| Counter {
+                    status,
+                }))
+            }))
+            .unwrap();
+
+        let counted = join_all((0..7).map(|_| pool.next().send(Count)).collect::<Vec<_>>());
+        system.block_on(counted).unwrap();
+
+        assert_eq!(counts(&pool), vec![3, 2, 2]);
+        pool.stop();
+    }
+
+    #[test]
+    fn test_empty_pool() {
+        let mut system = System::new("test");
+        let pool = system
+            .block_on(future::lazy(|| {
+                Ok::<_, ()>(ExecutorPool::start(0, "se.zcheck", |status| Counter {
+                    status,
+                }))
+            }))
+            .unwrap();
+
+        assert_eq!(pool.executors().len(), 1);
+        system.block_on(pool.next().send(Count)).unwrap();
+        assert_eq!(counts(&pool), vec![1]);
+        pool.stop();
+    }
+}
@@ -0,0 +1,579 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::Command;
+
+use failure::Fail;
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// The UI language of descriptions listed without one
+pub const UNKNOWN_UI_LANGUAGE: &str = "und";
+
+const SECTION_MARKER: &str = "====";
+const TOGGLES_SECTION: &str = "Toggles:";
+const PIPELINES_SECTION: &str = "Pipelines:";
+const REGEX_MARKER: &str = "[regex]";
+
+lazy_static! {
+    /// A UI language named in a section header, like `==== UI language: en ====`
+    static ref UI_LANGUAGE: Regex =
+        Regex::new(r"(?i)\blang(?:uage)?\W+([a-z]{2,3}(?:[-_][a-z0-9]+)*)\b").expect("valid regex");
+}
+
+#[derive(Fail, Debug, Clone)]
+pub enum PreferencesError {
+    #[fail(display = "failed to run divvun-checker: {}", _0)]
+    Spawn(String),
+    #[fail(display = "divvun-checker exited with {}: {}", _0, _1)]
+    Failed(String, String),
+    #[fail(display = "invalid preferences on line {}: {}", _0, _1)]
+    Parse(usize, String),
+}
+
+/// Preferences of the grammar checkers by language, or why they couldn't be listed
+pub type GramcheckPreferences = HashMap<String, Result<GrammarPreferences, PreferencesError>>;
+
+/// A category of errors that can be turned on or off
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorCategory {
+    /// The error tag, or the pattern matching the error tags of a regex category if it is listed
+    pub id: Option<String>,
+    pub regex: bool,
+    /// Whether errors of the category are reported unless turned off
+    pub enabled_by_default: bool,
+    /// Descriptions by UI language
+    pub descriptions: BTreeMap<String, String>,
+    /// The compiled pattern of a listed regex category
+    #[serde(skip)]
+    pub pattern: Option<TagPattern>,
+}
+
+impl ErrorCategory {
+    /// The description in the UI language, falling back to any other
+    pub fn description(&self, ui_language: Option<&str>) -> Option<&str> {
+        ui_language
+            .and_then(|ui_language| self.descriptions.get(ui_language))
+            .or_else(|| self.descriptions.get(UNKNOWN_UI_LANGUAGE))
+            .or_else(|| self.descriptions.values().next())
+            .map(String::as_str)
+    }
+
+    /// Whether errors with the code belong to the category
+    pub fn matches(&self, error_code: &str) -> bool {
+        match &self.id {
+            Some(_) if self.regex => self
+                .pattern
+                .as_ref()
+                .map_or(false, |pattern| pattern.is_match(error_code)),
+            Some(id) => id == error_code,
+            None => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct GrammarPreferences {
+    /// Pipelines of the archive that can be chosen instead of its default one
+    pub pipelines: Vec<String>,
+    pub categories: Vec<ErrorCategory>,
+}
+
+impl GrammarPreferences {
+    /// Parses the output of `divvun-checker -p`. The `==== Pipelines: ====` section lists a
+    /// pipeline per line, while every `==== Toggles: ====` section lists the categories with
+    /// their descriptions in one UI language, named in the section's header or the header before
+    /// it, if at all:
+    ///
+    /// ```text
+    /// ==== Pipelines: ====
+    /// smegram
+    /// ==== Toggles: ====
+    /// - [x] typo      Spelling errors
+    /// - [ ] [regex]   Syntax errors
+    /// ```
+    ///
+    /// `[x]` marks categories that are on by default. Regex categories may list their pattern
+    /// before a tab, otherwise they are matched up between sections by their order. Lines of a
+    /// toggles section that aren't toggles are logged and skipped.
+    pub fn parse(output: &str) -> Result<Self, PreferencesError> {
+        let mut pipelines = vec![];
+        let mut categories: Vec<ErrorCategory> = vec![];
+        let mut ui_language = None;
+        let mut in_pipelines = false;
+        let mut in_toggles = false;
+        let mut regex_index = 0;
+
+        for (index, line) in output.lines().enumerate() {
+            let line = line.trim_end();
+
+            if let Some(header) = section_header(line) {
+                if let Some(language) = header_ui_language(header) {
+                    ui_language = Some(language);
+                }
+                in_pipelines = header == PIPELINES_SECTION;
+                in_toggles = header == TOGGLES_SECTION || header.starts_with("Toggles ");
+                regex_index = 0;
+                continue;
+            }
+
+            if in_pipelines {
+                let pipeline = line.trim();
+                if !pipeline.is_empty() && !pipelines.iter().any(|p| p == pipeline) {
+                    pipelines.push(pipeline.to_owned());
+                }
+                continue;
+            }
+
+            if !in_toggles || line.trim().is_empty() {
+                continue;
+            }
+
+            let toggle = match parse_toggle(line) {
+                Some(toggle) => toggle,
+                None => {
+                    warn!(
+                        "Skipping line {} of the grammar preferences, expected a toggle, got `{}`",
+                        index + 1,
+                        line
+                    );
+                    continue;
+                }
+            };
+
+            let language = ui_language
+                .clone()
+                .unwrap_or_else(|| UNKNOWN_UI_LANGUAGE.to_owned());
+
+            let existing = if toggle.regex && toggle.id.is_none() {
+                regex_index += 1;
+                categories
+                    .iter_mut()
+                    .filter(|category| category.regex && category.id.is_none())
+                    .nth(regex_index - 1)
+            } else {
+                categories
+                    .iter_mut()
+                    .find(|category| category.regex == toggle.regex && category.id == toggle.id)
+            };
+
+            match existing {
+                Some(category) => {
+                    category.descriptions.insert(language, toggle.description);
+                }
+                None => {
+                    let mut descriptions = BTreeMap::new();
+                    descriptions.insert(language, toggle.description);
+
+                    let pattern = match &toggle.id {
+                        Some(id) if toggle.regex => TagPattern::new(id),
+                        _ => None,
+                    };
+
+                    categories.push(ErrorCategory {
+                        id: toggle.id,
+                        pattern,
+                        regex: toggle.regex,
+                        enabled_by_default: toggle.enabled,
+                        descriptions,
+                    });
+                }
+            }
+        }
+
+        Ok(GrammarPreferences {
+            pipelines,
+            categories,
+        })
+    }
+
+    /// The descriptions of the error tags in the UI language, leaving out regex categories
+    pub fn error_tags(&self, ui_language: Option<&str>) -> BTreeMap<String, String> {
+        self.categories
+            .iter()
+            .filter(|category| !category.regex)
+            .filter_map(|category| {
+                let id = category.id.clone()?;
+                let description = category.description(ui_language).unwrap_or_default();
+                Some((id, description.to_owned()))
+            })
+            .collect()
+    }
+
+    /// The UI languages the grammar checker has messages in
+    pub fn ui_languages(&self) -> BTreeSet<&str> {
+        self.categories
+            .iter()
+            .flat_map(|category| category.descriptions.keys())
+            .map(String::as_str)
+            .filter(|language| *language != UNKNOWN_UI_LANGUAGE)
+            .collect()
+    }
+
+    /// The first of the requested UI languages the grammar checker has messages in, matching
+    /// `en-GB` to `en` and the other way around if there is no exact match
+    pub fn negotiate_ui_language<S: AsRef<str>>(&self, requested: &[S]) -> Option<String> {
+        let available = self.ui_languages();
+
+        requested.iter().find_map(|requested| {
+            let requested = requested.as_ref();
+
+            available
+                .iter()
+                .find(|language| language.eq_ignore_ascii_case(requested))
+                .or_else(|| {
+                    available
+                        .iter()
+                        .find(|language| primary_subtag(language) == primary_subtag(requested))
+                })
+                .map(|language| (*language).to_owned())
+        })
+    }
+
+    /// Whether the tag can be used to turn errors on or off: an error tag, the pattern of a
+    /// regex category, or a tag matching that pattern
+    pub fn is_known_tag(&self, tag: &str) -> bool {
+        self.categories.iter().any(|category| {
+            category.id.as_ref().map_or(false, |id| id == tag) || category.matches(tag)
+        })
+    }
+}
+
+struct Toggle {
+    id: Option<String>,
+    regex: bool,
+    enabled: bool,
+    description: String,
+}
+
+fn section_header(line: &str) -> Option<&str> {
+    let line = line.trim();
+
+    if line.len() > 2 * SECTION_MARKER.len()
+        && line.starts_with(SECTION_MARKER)
+        && line.ends_with(SECTION_MARKER)
+    {
+        Some(line[SECTION_MARKER.len()..line.len() - SECTION_MARKER.len()].trim())
+    } else {
+        None
+    }
+}
+
+fn header_ui_language(header: &str) -> Option<String> {
+    UI_LANGUAGE
+        .captures(header)
+        .map(|captures| captures[1].to_owned())
+}
+
+fn parse_toggle(line: &str) -> Option<Toggle> {
+    let line = line.trim_start().strip_prefix("- [")?;
+    let mut chars = line.chars();
+    let enabled = match chars.next()? {
+        ' ' => false,
+        'x' | 'X' => true,
+        _ => return None,
+    };
+    let rest = chars.as_str().strip_prefix(']')?.trim_start();
+
+    if let Some(rest) = rest.strip_prefix(REGEX_MARKER) {
+        let rest = rest.trim_start_matches(' ');
+        let (id, description) = match rest.find('\t') {
+            Some(tab) if tab > 0 => (Some(rest[..tab].trim().to_owned()), &rest[tab..]),
+            _ => (None, rest),
+        };
+
+        return Some(Toggle {
+            id,
+            regex: true,
+            enabled,
+            description: description.trim().to_owned(),
+        });
+    }
+
+    let mut parts = rest.splitn(2, char::is_whitespace);
+    let id = parts.next().filter(|id| !id.is_empty())?;
+
+    Some(Toggle {
+        id: Some(id.to_owned()),
+        regex: false,
+        enabled,
+        description: parts.next().unwrap_or("").trim().to_owned(),
+    })
+}
+
+/// The language of a language tag, with the Norwegian macrolanguage taken as Bokmål
+fn primary_subtag(language: &str) -> String {
+    let primary = language
+        .split(|c| c == '-' || c == '_')
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+
+    match primary.as_str() {
+        "no" => "nb".to_owned(),
+        _ => primary,
+    }
+}
+
+/// The languages of an `Accept-Language` header, most preferred first
+pub fn accept_languages(header: &str) -> Vec<String> {
+    let mut languages: Vec<(f32, &str)> = header
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let language = parts.next()?.trim();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .next()
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+
+            if language.is_empty() || language == "*" || quality <= 0.0 {
+                None
+            } else {
+                Some((quality, language))
+            }
+        })
+        .collect();
+
+    // The sort is stable, so languages of the same quality keep their order
+    languages.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    languages
+        .into_iter()
+        .map(|(_, language)| language.to_owned())
+        .collect()
+}
+
+/// The pattern of a regex category, compiled to match whole error tags
+#[derive(Debug, Clone)]
+pub struct TagPattern(Regex);
+
+impl TagPattern {
+    fn new(pattern: &str) -> Option<Self> {
+        Regex::new(&format!("^(?:{})$", pattern))
+            .ok()
+            .map(TagPattern)
+    }
+
+    pub fn is_match(&self, error_code: &str) -> bool {
+        self.0.is_match(error_code)
+    }
+}
+
+impl PartialEq for TagPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+/// A tag given by a client to turn errors on or off
+#[derive(Debug, Clone, PartialEq)]
+enum TagMatcher {
+    Tag(String),
+    /// The pattern of one of the language's regex categories
+    Pattern(TagPattern),
+}
+
+impl TagMatcher {
+    fn new(tag: &str, preferences: Option<&GrammarPreferences>) -> Self {
+        preferences
+            .and_then(|preferences| {
+                preferences
+                    .categories
+                    .iter()
+                    .find(|category| category.regex && category.id.as_deref() == Some(tag))
+            })
+            .and_then(|category| category.pattern.clone())
+            .map(TagMatcher::Pattern)
+            .unwrap_or_else(|| TagMatcher::Tag(tag.to_owned()))
+    }
+
+    fn matches(&self, error_code: &str) -> bool {
+        match self {
+            TagMatcher::Tag(tag) => tag == error_code,
+            TagMatcher::Pattern(pattern) => pattern.is_match(error_code),
+        }
+    }
+}
+
+/// The error tags a request turns off or limits its errors to. Only the patterns of the
+/// language's regex categories are matched as patterns, every other tag is matched as is.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TagFilter {
+    ignore: Vec<TagMatcher>,
+    only: Option<Vec<TagMatcher>>,
+}
+
+impl TagFilter {
+    pub fn new(
+        ignore_tags: &[String],
+        only_tags: Option<&[String]>,
+        preferences: Option<&GrammarPreferences>,
+    ) -> Self {
+        let matchers = |tags: &[String]| {
+            tags.iter()
+                .map(|tag| TagMatcher::new(tag, preferences))
+                .collect()
+        };
+
+        TagFilter {
+            ignore: matchers(ignore_tags),
+            only: only_tags.map(matchers),
+        }
+    }
+
+    /// Whether errors with the code are reported
+    pub fn is_reported(&self, error_code: &str) -> bool {
+        if self.ignore.iter().any(|tag| tag.matches(error_code)) {
+            return false;
+        }
+
+        match &self.only {
+            Some(only) => only.iter().any(|tag| tag.matches(error_code)),
+            None => true,
+        }
+    }
+}
+
+pub fn list_preferences(data_file_path: &str) -> Result<GrammarPreferences, PreferencesError> {
+    let output = Command::new("divvun-checker")
+        .arg("-a")
+        .arg(data_file_path)
+        .arg("-p")
+        .output()
+        .map_err(|e| PreferencesError::Spawn(e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(PreferencesError::Failed(
+            output.status.to_string(),
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|e| PreferencesError::Parse(0, format!("output is not UTF-8: {}", e)))?;
+
+    GrammarPreferences::parse(&stdout)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OUTPUT: &str = "\
+==== Pipelines: ====
+smegram
+smegram-dev
+==== Toggles: ====
+- [x] typo \tČállinmeattáhusat
+- [ ] msyn-agr \tKongrueansa
+- [ ] [regex] \tSyntaksa
+==== Options: ====
+==== UI language: en ====
+==== Toggles: ====
+- [x] typo \tSpelling errors
+- [ ] msyn-agr \tAgreement
+- [ ] [regex] \tSyntax
+- [x] [regex] punct-.* \tPunctuation
+";
+
+    #[test]
+    fn test_parse() {
+        let preferences = GrammarPreferences::parse(OUTPUT).unwrap();
+        let categories = &preferences.categories;
+
+        assert_eq!(preferences.pipelines, vec!["smegram", "smegram-dev"]);
+        assert_eq!(categories.len(), 4);
+
+        assert_eq!(categories[0].id.as_deref(), Some("typo"));
+        assert!(categories[0].enabled_by_default);
+        assert_eq!(
+            categories[0].description(Some("en")),
+            Some("Spelling errors")
+        );
+        assert_eq!(
+            categories[0].description(Some("se")),
+            Some("Čállinmeattáhusat")
+        );
+
+        assert!(!categories[1].enabled_by_default);
+
+        assert!(categories[2].regex);
+        assert_eq!(categories[2].id, None);
+        assert_eq!(categories[2].descriptions.len(), 2);
+
+        assert_eq!(categories[3].id.as_deref(), Some("punct-.*"));
+        assert!(categories[3].matches("punct-comma"));
+
+        assert_eq!(
+            preferences
+                .error_tags(Some("en"))
+                .get("msyn-agr")
+                .map(String::as_str),
+            Some("Agreement")
+        );
+        assert!(preferences.is_known_tag("punct-comma"));
+        assert!(!preferences.is_known_tag("lex"));
+    }
+
+    #[test]
+    fn test_parse_invalid_line() {
+        let output = "==== Toggles: ====\n- [x] typo \tSpelling errors\n- [?] broken\n\
+                      - [ ] msyn-agr \tAgreement\n";
+
+        let preferences = GrammarPreferences::parse(output).unwrap();
+        let ids: Vec<_> = preferences
+            .categories
+            .iter()
+            .map(|category| category.id.as_deref())
+            .collect();
+        assert_eq!(ids, vec![Some("typo"), Some("msyn-agr")]);
+    }
+
+    #[test]
+    fn test_negotiate_ui_language() {
+        let preferences = GrammarPreferences::parse(
+            "==== UI language: en ====\n==== Toggles: ====\n- [x] typo \tSpelling errors\n\
+             ==== UI language: nb ====\n==== Toggles: ====\n- [x] typo \tSkrivefeil\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            preferences.negotiate_ui_language(&["fr", "en-GB"]),
+            Some("en".to_owned())
+        );
+        assert_eq!(
+            preferences.negotiate_ui_language(&["no"]),
+            Some("nb".to_owned())
+        );
+        assert_eq!(preferences.negotiate_ui_language(&["fr"]), None);
+    }
+
+    #[test]
+    fn test_accept_languages() {
+        assert_eq!(
+            accept_languages("nn;q=0.5, nb-NO, en;q=0.8, *;q=0.1, de;q=0"),
+            vec!["nb-NO", "en", "nn"]
+        );
+    }
+
+    #[test]
+    fn test_tag_filter() {
+        let preferences = GrammarPreferences::parse(OUTPUT).unwrap();
+        let tags = |tags: &[&str]| tags.iter().map(|tag| (*tag).to_owned()).collect::<Vec<_>>();
+
+        let filter = TagFilter::new(&tags(&["typo", "punct-.*"]), None, Some(&preferences));
+        assert!(!filter.is_reported("typo"));
+        assert!(!filter.is_reported("punct-comma"));
+        assert!(filter.is_reported("typos"));
+        assert!(filter.is_reported("msyn-agr"));
+
+        // Only the patterns of regex categories are patterns
+        let filter = TagFilter::new(&[], Some(&tags(&["msyn-.*"])), Some(&preferences));
+        assert!(!filter.is_reported("msyn-agr"));
+        assert!(filter.is_reported("msyn-.*"));
+
+        let filter = TagFilter::new(&tags(&["punct-.*"]), None, None);
+        assert!(filter.is_reported("punct-comma"));
+    }
+}
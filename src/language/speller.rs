@@ -1,21 +1,30 @@
 use std::sync::Arc;
+use std::time::Instant;
 
 use actix::prelude::*;
 use divvunspell::archive::{zip::ZipSpellerArchive, SpellerArchive};
-use futures::future::{err, ok, Future};
+use futures::future::{err, join_all, ok, Future};
 use hashbrown::HashMap;
 use log::{info, warn};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 
+use crate::config::{language_settings, Languages, SpellerSettings};
 use crate::error::ApiError;
+use crate::language::pool::ExecutorPool;
+use crate::metrics::{seconds, SPELLER_SUGGESTION_DURATION, WORDS_PROCESSED};
 use crate::server::state::{LanguageSuggestions, UnhoistFutureExt};
+use crate::server::status::{LanguageStatus, SharedWorkerStatus};
 use divvunspell::speller::suggestion::Suggestion;
+use divvunspell::speller::SpellerConfig;
 use divvunspell::tokenizer::Tokenize;
+use divvunspell::types::Weight;
 
 pub struct DivvunSpellExecutor {
     pub speller_archive: Arc<dyn SpellerArchive>,
     pub language: String,
+    pub settings: SpellerSettings,
+    pub status: SharedWorkerStatus,
     pub terminated: bool,
 }
 
@@ -27,13 +36,56 @@ impl actix::Supervised for DivvunSpellExecutor {
     fn restarting(&mut self, _ctx: &mut Context<DivvunSpellExecutor>) {
         if !self.terminated {
             warn!("Actor for {} died, restarting", &self.language);
+            self.status.write().restarts += 1;
         }
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct SpellerRequest {
     pub text: String,
+    pub max_suggestions: Option<usize>,
+    pub max_weight: Option<Weight>,
+    pub beam: Option<Weight>,
+    /// Whether suggestions are also looked up for differently cased forms of the word
+    pub recase: Option<bool>,
+    /// Whether suggestions are generated for correctly spelled words
+    pub suggest_correct: Option<bool>,
+    /// Whether only misspelled words are returned
+    #[serde(default)]
+    pub only_misspelled: bool,
+}
+
+impl SpellerRequest {
+    /// Combines the options of the request with the server's defaults, capped by its limits
+    fn speller_config(&self, settings: &SpellerSettings) -> SpellerConfig {
+        let mut config = SpellerConfig::default();
+
+        let max_suggestions = self.max_suggestions.unwrap_or(settings.max_suggestions);
+        config.n_best = Some(max_suggestions.min(settings.max_suggestions_limit));
+
+        if let Some(max_weight) = self.max_weight.or(settings.max_weight) {
+            config.max_weight = Some(max_weight);
+        }
+        config.max_weight = cap(config.max_weight, settings.max_weight_limit);
+
+        if let Some(beam) = self.beam.or(settings.beam) {
+            config.beam = Some(beam);
+        }
+        config.beam = cap(config.beam, settings.beam_limit);
+
+        config.recase = self.recase.unwrap_or(settings.recase);
+
+        config
+    }
+}
+
+fn cap(value: Option<Weight>, limit: Option<Weight>) -> Option<Weight> {
+    match (value, limit) {
+        (Some(value), Some(limit)) => Some(value.min(limit)),
+        (None, Some(limit)) => Some(limit),
+        (value, None) => value,
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -47,6 +99,45 @@ pub struct SpellerResult {
     pub word: String,
     pub is_correct: bool,
     pub suggestions: Vec<Suggestion>,
+    /// Offsets of the word within the text in UTF-8 bytes
+    pub start_byte: u32,
+    pub end_byte: u32,
+    /// Offsets of the word within the text in UTF-16 code units
+    pub start_utf16: u32,
+    pub end_utf16: u32,
+    /// Offsets of the word within the text in Unicode code points
+    pub start_char: u32,
+    pub end_char: u32,
+}
+
+/// Converts increasing byte offsets of a text into UTF-16 and code point offsets
+struct OffsetCounter<'a> {
+    text: &'a str,
+    byte: usize,
+    utf16: u32,
+    char: u32,
+}
+
+impl<'a> OffsetCounter<'a> {
+    fn new(text: &'a str) -> Self {
+        OffsetCounter {
+            text,
+            byte: 0,
+            utf16: 0,
+            char: 0,
+        }
+    }
+
+    /// Moves forward to the byte offset, returning the UTF-16 and code point offsets
+    fn advance(&mut self, byte: usize) -> (u32, u32) {
+        let skipped = &self.text[self.byte..byte];
+
+        self.utf16 += skipped.encode_utf16().count() as u32;
+        self.char += skipped.chars().count() as u32;
+        self.byte = byte;
+
+        (self.utf16, self.char)
+    }
 }
 
 impl Message for SpellerRequest {
@@ -58,23 +149,52 @@ impl Handler<SpellerRequest> for DivvunSpellExecutor {
 
     fn handle(&mut self, msg: SpellerRequest, _: &mut Self::Context) -> Self::Result {
         let speller = self.speller_archive.speller();
+        let config = msg.speller_config(&self.settings);
+        let suggest_correct = msg.suggest_correct.unwrap_or(self.settings.suggest_correct);
 
         let cloned_text = msg.text.clone();
-        let words = cloned_text.word_indices().map(|x| x.1).into_iter();
-        // let words = cloned_text.words().into_iter();
+        let mut offsets = OffsetCounter::new(&cloned_text);
+
+        let words = WORDS_PROCESSED.with_label_values(&["speller", &self.language]);
+
+        let results: Vec<SpellerResult> = cloned_text
+            .word_indices()
+            .filter_map(|(start, word)| {
+                words.inc();
 
-        let results: Vec<SpellerResult> = words
-            .map(|word| {
-                let cloned_speller = self.speller_archive.speller().clone();
                 let is_correct = Arc::clone(&speller).is_correct(word);
+                if is_correct && msg.only_misspelled {
+                    return None;
+                }
 
-                let suggestions = cloned_speller.suggest(word).into_iter().collect();
+                let cloned_speller = self.speller_archive.speller().clone();
 
-                SpellerResult {
+                let suggestions = if is_correct && !suggest_correct {
+                    vec![]
+                } else {
+                    let start = Instant::now();
+                    let suggestions = cloned_speller.suggest_with_config(word, &config);
+                    SPELLER_SUGGESTION_DURATION
+                        .with_label_values(&[&self.language])
+                        .observe(seconds(start.elapsed()));
+                    suggestions
+                };
+
+                let end = start + word.len();
+                let (start_utf16, start_char) = offsets.advance(start);
+                let (end_utf16, end_char) = offsets.advance(end);
+
+                Some(SpellerResult {
                     word: word.to_owned(),
                     is_correct,
                     suggestions,
-                }
+                    start_byte: start as u32,
+                    end_byte: end as u32,
+                    start_utf16,
+                    end_utf16,
+                    start_char,
+                    end_char,
+                })
             })
             .collect();
 
@@ -99,7 +219,9 @@ impl Handler<Die> for DivvunSpellExecutor {
 }
 
 pub struct AsyncSpeller {
-    pub spellers: Arc<RwLock<HashMap<String, Addr<DivvunSpellExecutor>>>>,
+    pub spellers: Arc<RwLock<HashMap<String, ExecutorPool<DivvunSpellExecutor>>>>,
+    pub settings: SpellerSettings,
+    pub languages: Languages,
 }
 
 impl LanguageSuggestions for AsyncSpeller {
@@ -114,11 +236,12 @@ impl LanguageSuggestions for AsyncSpeller {
         let lock = self.spellers.read();
 
         let speller = match lock.get(language) {
-            Some(s) => s,
+            Some(s) => s.next(),
             None => {
-                return Box::new(err(ApiError {
-                    message: format!("No speller available for language {}", &language),
-                }));
+                return Box::new(err(ApiError::LanguageNotFound(format!(
+                    "No speller available for language {}",
+                    &language
+                ))));
             }
         };
 
@@ -127,11 +250,11 @@ impl LanguageSuggestions for AsyncSpeller {
         Box::new(
             speller
                 .send(message)
-                .map_err(move |err| ApiError {
-                    message: format!(
+                .map_err(move |err| {
+                    ApiError::BackendUnavailable(format!(
                         "Something failed in the message delivery process for language {}: {}",
                         &language, err
-                    ),
+                    ))
                 })
                 .unhoist(),
         )
@@ -145,13 +268,18 @@ impl LanguageSuggestions for AsyncSpeller {
         let speller_path = path.to_owned();
 
         let owned_language = language.to_owned();
-        let speller = actix::Supervisor::start_in_arbiter(&actix::Arbiter::new(), move |_| {
+        let settings = self
+            .settings
+            .with_overrides(&language_settings(&self.languages, language).speller);
+        let speller = ExecutorPool::start(1, path, move |status| {
             let ar = ZipSpellerArchive::open(&std::path::Path::new(&speller_path))
                 .map(|x| Arc::new(x) as _)
                 .unwrap();
             DivvunSpellExecutor {
                 speller_archive: ar,
-                language: owned_language,
+                language: owned_language.clone(),
+                settings: settings.clone(),
+                status,
                 terminated: false,
             }
         });
@@ -169,31 +297,129 @@ impl LanguageSuggestions for AsyncSpeller {
         let speller = match lock.remove(language) {
             Some(s) => s,
             None => {
-                return Box::new(err(ApiError {
-                    message: format!("No speller available for language {}", &language),
-                }));
+                return Box::new(err(ApiError::LanguageNotFound(format!(
+                    "No speller available for language {}",
+                    &language
+                ))));
             }
         };
 
         let cloned_spellers = Arc::clone(&self.spellers);
         let language = language.to_owned();
 
-        Box::new(
+        let deaths = join_all(
             speller
-                .send(Die)
-                .map_err(move |err| {
-                    // Put the address back in since we failed to send the die message
-                    let mut lock = cloned_spellers.write();
-                    lock.insert(language.clone(), speller);
-
-                    ApiError {
-                        message: format!(
-                            "Something failed in the message delivery process for language {}: {}",
-                            &language, err
-                        ),
-                    }
-                })
-                .and_then(|_| ok(())),
-        )
+                .executors()
+                .iter()
+                .map(|executor| executor.send(Die))
+                .collect::<Vec<_>>(),
+        );
+
+        Box::new(deaths.then(move |result| match result {
+            Ok(_) => {
+                // The executors are gone, so the threads they ran on can go too
+                speller.stop();
+                Ok(())
+            }
+            Err(err) => {
+                // Put the pool back in since we failed to send the die message
+                let mut lock = cloned_spellers.write();
+                lock.insert(language.clone(), speller);
+
+                Err(ApiError::BackendUnavailable(format!(
+                    "Something failed in the message delivery process for language {}: {}",
+                    &language, err
+                )))
+            }
+        }))
+    }
+
+    fn has_language(&self, language: &str) -> bool {
+        self.spellers.read().contains_key(language)
+    }
+
+    fn status(&self) -> Vec<LanguageStatus> {
+        let lock = self.spellers.read();
+
+        lock.iter()
+            .map(|(language, pool)| pool.status(language))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cap, OffsetCounter, SpellerConfig, SpellerRequest, SpellerSettings};
+
+    #[test]
+    fn test_offset_counter() {
+        let text = "oainá 𝔭áhkat";
+        let mut offsets = OffsetCounter::new(text);
+
+        assert_eq!(offsets.advance(0), (0, 0));
+        assert_eq!(offsets.advance(6), (5, 5));
+        assert_eq!(offsets.advance(7), (6, 6));
+        assert_eq!(offsets.advance(text.len()), (13, 12));
+    }
+
+    #[test]
+    fn test_cap() {
+        assert_eq!(cap(Some(5.0), Some(10.0)), Some(5.0));
+        assert_eq!(cap(Some(15.0), Some(10.0)), Some(10.0));
+        assert_eq!(cap(None, Some(10.0)), Some(10.0));
+        assert_eq!(cap(Some(15.0), None), Some(15.0));
+        assert_eq!(cap(None, None), None);
+    }
+
+    #[test]
+    fn test_speller_config() {
+        let settings = SpellerSettings {
+            max_suggestions: 10,
+            max_suggestions_limit: 20,
+            max_weight: Some(5000.0),
+            max_weight_limit: Some(8000.0),
+            beam: None,
+            beam_limit: Some(20.0),
+            recase: true,
+            ..Default::default()
+        };
+
+        // Under the limits the request's options are used
+        let config = SpellerRequest {
+            max_suggestions: Some(15),
+            max_weight: Some(7000.0),
+            beam: Some(10.0),
+            recase: Some(false),
+            ..Default::default()
+        }
+        .speller_config(&settings);
+        assert_eq!(config.n_best, Some(15));
+        assert_eq!(config.max_weight, Some(7000.0));
+        assert_eq!(config.beam, Some(10.0));
+        assert!(!config.recase);
+
+        // Over the limits they are capped
+        let config = SpellerRequest {
+            max_suggestions: Some(100),
+            max_weight: Some(9000.0),
+            beam: Some(30.0),
+            ..Default::default()
+        }
+        .speller_config(&settings);
+        assert_eq!(config.n_best, Some(20));
+        assert_eq!(config.max_weight, Some(8000.0));
+        assert_eq!(config.beam, Some(20.0));
+
+        // Without options the defaults are used, and a limit without a default is the default
+        let config = SpellerRequest::default().speller_config(&settings);
+        assert_eq!(config.n_best, Some(10));
+        assert_eq!(config.max_weight, Some(5000.0));
+        assert_eq!(config.beam, Some(20.0));
+        assert!(config.recase);
+
+        // Without limits either, divvunspell's defaults are kept
+        let config = SpellerRequest::default().speller_config(&SpellerSettings::default());
+        assert_eq!(config.max_weight, SpellerConfig::default().max_weight);
+        assert_eq!(config.beam, SpellerConfig::default().beam);
     }
 }
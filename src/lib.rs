@@ -4,5 +4,7 @@ pub mod file_utils;
 pub mod graphql;
 pub mod init;
 pub mod language;
+pub mod lsp;
+pub mod metrics;
 pub mod server;
 pub mod watcher;
@@ -0,0 +1,1068 @@
+//! A Language Server Protocol frontend for the speller and grammar checker, so that editors can
+//! show their results as diagnostics and apply suggestions as code actions
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use actix::{Arbiter, System};
+use futures::future::{self, ok, Future};
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::error::ApiError;
+use crate::language::grammar::{GramcheckRequest, GramcheckResponse};
+use crate::language::speller::{SpellerRequest, SpellerResponse};
+use crate::server::auth::Client;
+use crate::server::state::State;
+
+pub mod protocol;
+
+use self::protocol::{
+    error_response, notification, read_message, response, write_message, CodeAction,
+    CodeActionParams, Diagnostic, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, LspSettings, Message, Position, Range, ResponseError, TextEdit,
+    WorkspaceEdit, INVALID_PARAMS, METHOD_NOT_FOUND, REQUEST_FAILED, SEVERITY_INFORMATION,
+    SEVERITY_WARNING, TEXT_DOCUMENT_SYNC_FULL,
+};
+
+const SOURCE: &str = "divvun";
+
+/// Number of lines at the start of a document searched for a language hint
+const HINT_LINES: usize = 10;
+
+lazy_static! {
+    /// A hint like `divvun-language: sma`, usually in a comment at the top of the document
+    static ref LANGUAGE_HINT: Regex =
+        Regex::new(r"divvun-language:\s*([A-Za-z0-9_-]+)").expect("valid regex");
+}
+
+pub enum Transport {
+    Stdio,
+    Tcp(String),
+}
+
+/// Starts serving the language server protocol. Over stdio the system is stopped once the
+/// client exits, over TCP every connection is served until it is closed. TCP clients are held to
+/// the same API keys and rate limits as clients of the HTTP server.
+pub fn start(state: State, transport: Transport, system: System) -> io::Result<()> {
+    let arbiter = system.arbiter().clone();
+
+    match transport {
+        Transport::Stdio => {
+            thread::spawn(move || {
+                let stdin = io::stdin();
+                let access = Access::default();
+                let result = serve(state, arbiter, access, stdin.lock(), Box::new(io::stdout()));
+                if let Err(e) = result {
+                    error!("Language server failed: {}", e);
+                }
+                system.stop();
+            });
+        }
+        Transport::Tcp(addr) => {
+            let listener = TcpListener::bind(&addr)?;
+            info!("Language server listening on {}", addr);
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let state = state.clone();
+                            let arbiter = arbiter.clone();
+                            thread::spawn(move || serve_tcp(state, arbiter, stream));
+                        }
+                        Err(e) => warn!("Failed to accept language server connection: {}", e),
+                    }
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn serve_tcp(state: State, arbiter: Arbiter, stream: TcpStream) {
+    let peer = stream.peer_addr().ok();
+    debug!("Language server connection from {:?}", peer);
+
+    let access = Access {
+        key_required: state.authenticator.enabled(),
+        client: None,
+        client_ip: peer.map(|addr| addr.ip()),
+    };
+
+    let result = stream.try_clone().and_then(|reader| {
+        serve(
+            state,
+            arbiter,
+            access,
+            BufReader::new(reader),
+            Box::new(stream),
+        )
+    });
+
+    if let Err(e) = result {
+        warn!("Language server connection from {:?} failed: {}", peer, e);
+    }
+}
+
+type Output = Arc<Mutex<Box<dyn Write + Send>>>;
+type Documents = Arc<Mutex<HashMap<String, Document>>>;
+
+/// Who a client is, for holding it to the limits of its API key and IP address
+#[derive(Clone, Default)]
+struct Access {
+    /// Whether the client has to pass an API key in its `initializationOptions`
+    key_required: bool,
+    client: Option<Arc<Client>>,
+    client_ip: Option<IpAddr>,
+}
+
+impl Access {
+    fn authenticated(&self) -> bool {
+        !self.key_required || self.client.is_some()
+    }
+
+    /// Counts a check of a document against the limits of the client's IP address
+    fn limit_by_ip(&self, state: &State) -> Result<(), ApiError> {
+        match self.client_ip {
+            Some(client_ip) => state.rate_limiter.check(client_ip, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a check by the service against the limits of the client's API key
+    fn count(&self, state: &State, service: &str, language: &str) -> Result<(), ApiError> {
+        match &self.client {
+            Some(client) => client
+                .authorize(service, language)
+                .and_then(|_| state.authenticator.count(client)),
+            None => Ok(()),
+        }
+    }
+}
+
+type SharedAccess = Arc<Mutex<Access>>;
+
+fn send(output: &Output, message: &Value) -> io::Result<()> {
+    write_message(&mut **output.lock(), message)
+}
+
+/// A diagnostic along with the suggestions offered as code actions
+#[derive(Debug, Clone)]
+struct Fix {
+    diagnostic: Diagnostic,
+    suggestions: Vec<String>,
+}
+
+struct Document {
+    text: String,
+    version: i64,
+    language: Option<String>,
+    /// The fixes for the current version, empty until it has been checked
+    fixes: Vec<Fix>,
+}
+
+/// The language of a document, taken from a hint in the document or else the settings
+fn document_language(text: &str, settings: &LspSettings) -> Option<String> {
+    text.lines()
+        .take(HINT_LINES)
+        .find_map(|line| LANGUAGE_HINT.captures(line))
+        .map(|captures| captures[1].to_owned())
+        .or_else(|| settings.language.clone())
+}
+
+/// Serves a single client until it exits or closes the connection. The documents are checked on
+/// the given arbiter of the actix system.
+fn serve<R: BufRead>(
+    state: State,
+    arbiter: Arbiter,
+    access: Access,
+    mut reader: R,
+    writer: Box<dyn Write + Send>,
+) -> io::Result<()> {
+    let output: Output = Arc::new(Mutex::new(writer));
+    let documents: Documents = Arc::new(Mutex::new(HashMap::new()));
+    let access: SharedAccess = Arc::new(Mutex::new(access));
+    let (checks, queue) = mpsc::channel();
+
+    let checker = Checker {
+        state: Arc::clone(&state),
+        arbiter,
+        access: Arc::clone(&access),
+        documents: Arc::clone(&documents),
+        output: Arc::clone(&output),
+    };
+    thread::spawn(move || checker.run(queue));
+
+    let mut server = LanguageServer {
+        state,
+        access,
+        documents,
+        output,
+        checks,
+        settings: LspSettings::default(),
+    };
+
+    while let Some(message) = read_message(&mut reader)? {
+        if !server.handle(message)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, ResponseError> {
+    serde_json::from_value(params).map_err(|e| ResponseError {
+        code: INVALID_PARAMS,
+        message: format!("Invalid params: {}", e),
+    })
+}
+
+struct LanguageServer {
+    state: State,
+    access: SharedAccess,
+    documents: Documents,
+    output: Output,
+    /// Documents to be checked
+    checks: Sender<String>,
+    settings: LspSettings,
+}
+
+impl LanguageServer {
+    /// Handles a message from the client, returning false once the client exits
+    fn handle(&mut self, message: Message) -> io::Result<bool> {
+        // Responses to requests of the server carry no method and need no handling
+        let method = match message.method {
+            Some(method) => method,
+            None => return Ok(true),
+        };
+
+        if method == "exit" {
+            return Ok(false);
+        }
+
+        // Clients that need an API key can only initialize until they have passed one
+        if method != "initialize" && !self.access.lock().authenticated() {
+            if let Some(id) = message.id {
+                let error = ResponseError {
+                    code: REQUEST_FAILED,
+                    message: "The server hasn't been initialized with an API key".to_owned(),
+                };
+                send(&self.output, &error_response(id, error))?;
+            }
+            return Ok(true);
+        }
+
+        let id = match message.id {
+            Some(id) => id,
+            None => {
+                self.notify(&method, message.params);
+                return Ok(true);
+            }
+        };
+
+        let result = match method.as_str() {
+            "initialize" => self.initialize(message.params),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/codeAction" => params(message.params).map(|p| self.code_actions(p)),
+            _ => Err(ResponseError {
+                code: METHOD_NOT_FOUND,
+                message: format!("Unsupported method {}", method),
+            }),
+        };
+
+        let reply = match result {
+            Ok(result) => response(id, result),
+            Err(e) => error_response(id, e),
+        };
+        send(&self.output, &reply)?;
+
+        Ok(true)
+    }
+
+    fn notify(&mut self, method: &str, params_value: Value) {
+        let result = match method {
+            "textDocument/didOpen" => params(params_value).map(|p| self.open(p)),
+            "textDocument/didChange" => params(params_value).map(|p| self.change(p)),
+            "textDocument/didClose" => params(params_value).map(|p| self.close(p)),
+            "workspace/didChangeConfiguration" => {
+                if let Some(settings) = params_value.pointer("/settings/divvun") {
+                    self.update_settings(settings);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+
+        if let Err(e) = result {
+            warn!("Ignoring {} notification: {}", method, e.message);
+        }
+    }
+
+    fn initialize(&mut self, params: Value) -> Result<Value, ResponseError> {
+        self.authenticate(&params)?;
+
+        if let Some(options) = params.get("initializationOptions") {
+            self.update_settings(options);
+        }
+
+        Ok(json!({
+            "capabilities": {
+                "textDocumentSync": {
+                    "openClose": true,
+                    "change": TEXT_DOCUMENT_SYNC_FULL,
+                },
+                "codeActionProvider": true,
+            },
+            "serverInfo": {
+                "name": "divvun-api",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+        }))
+    }
+
+    /// Identifies the client by the `key` of its `initializationOptions`, if it needs one
+    fn authenticate(&self, params: &Value) -> Result<(), ResponseError> {
+        let mut access = self.access.lock();
+        if !access.key_required {
+            return Ok(());
+        }
+
+        let key = params
+            .pointer("/initializationOptions/key")
+            .and_then(Value::as_str)
+            .ok_or_else(|| ResponseError {
+                code: REQUEST_FAILED,
+                message: "An API key is required, pass it as `key` in the initializationOptions"
+                    .to_owned(),
+            })?;
+
+        match self.state.authenticator.authenticate(Some(key)) {
+            Ok(client) => {
+                access.client = client;
+                Ok(())
+            }
+            Err(e) => Err(ResponseError {
+                code: REQUEST_FAILED,
+                message: e.message().to_owned(),
+            }),
+        }
+    }
+
+    fn update_settings(&mut self, settings: &Value) {
+        match serde_json::from_value::<LspSettings>(settings.clone()) {
+            Ok(settings) => self.settings = settings,
+            Err(e) => {
+                warn!("Ignoring invalid language server settings: {}", e);
+                return;
+            }
+        }
+
+        // The language of documents without a hint may have changed
+        let mut documents = self.documents.lock();
+        for (uri, document) in documents.iter_mut() {
+            document.language = document_language(&document.text, &self.settings);
+            document.fixes.clear();
+            self.check(uri.clone());
+        }
+    }
+
+    fn check(&self, uri: String) {
+        // The checker only stops once the server is dropped
+        let _ = self.checks.send(uri);
+    }
+
+    fn open(&mut self, params: DidOpenTextDocumentParams) {
+        let item = params.text_document;
+        let language = document_language(&item.text, &self.settings);
+
+        self.documents.lock().insert(
+            item.uri.clone(),
+            Document {
+                text: item.text,
+                version: item.version,
+                language,
+                fixes: vec![],
+            },
+        );
+        self.check(item.uri);
+    }
+
+    fn change(&mut self, params: DidChangeTextDocumentParams) {
+        let uri = params.text_document.uri;
+
+        // With full synchronization the last change holds the whole document
+        let text = match params.content_changes.into_iter().last() {
+            Some(change) => change.text,
+            None => return,
+        };
+
+        let mut documents = self.documents.lock();
+        let language = document_language(&text, &self.settings);
+        documents.insert(
+            uri.clone(),
+            Document {
+                text,
+                version: params.text_document.version,
+                language,
+                fixes: vec![],
+            },
+        );
+        self.check(uri);
+    }
+
+    fn close(&mut self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri;
+
+        self.documents.lock().remove(&uri);
+
+        let params = json!({ "uri": uri, "diagnostics": [] });
+        if let Err(e) = send(
+            &self.output,
+            &notification("textDocument/publishDiagnostics", params),
+        ) {
+            warn!("Failed to clear diagnostics of {}: {}", uri, e);
+        }
+    }
+
+    fn code_actions(&self, params: CodeActionParams) -> Value {
+        let uri = params.text_document.uri;
+        let documents = self.documents.lock();
+
+        let fixes = match documents.get(&uri) {
+            Some(document) => &document.fixes,
+            None => return json!([]),
+        };
+
+        let actions: Vec<CodeAction> = fixes
+            .iter()
+            .filter(|fix| fix.diagnostic.range.overlaps(&params.range))
+            .flat_map(|fix| {
+                let uri = &uri;
+                fix.suggestions.iter().map(move |suggestion| {
+                    let mut changes = HashMap::new();
+                    changes.insert(
+                        uri.clone(),
+                        vec![TextEdit {
+                            range: fix.diagnostic.range,
+                            new_text: suggestion.clone(),
+                        }],
+                    );
+
+                    CodeAction {
+                        title: format!("Replace with \"{}\"", suggestion),
+                        kind: "quickfix".to_owned(),
+                        diagnostics: vec![fix.diagnostic.clone()],
+                        edit: WorkspaceEdit { changes },
+                    }
+                })
+            })
+            .collect();
+
+        json!(actions)
+    }
+}
+
+/// Checks documents in the background and publishes their diagnostics, skipping versions that
+/// were replaced before their check finished
+struct Checker {
+    state: State,
+    /// The executors' mailboxes and request timeouts only work on the actix system
+    arbiter: Arbiter,
+    access: SharedAccess,
+    documents: Documents,
+    output: Output,
+}
+
+impl Checker {
+    fn run(self, queue: Receiver<String>) {
+        while let Ok(uri) = queue.recv() {
+            // Documents changed several times while the previous checks ran are checked once
+            let mut uris = vec![uri];
+            uris.extend(queue.try_iter());
+            uris.sort();
+            uris.dedup();
+
+            for uri in uris {
+                if let Err(e) = self.check(&uri) {
+                    warn!("Failed to publish diagnostics of {}: {}", uri, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn check(&self, uri: &str) -> io::Result<()> {
+        let (text, version, language) = match self.documents.lock().get(uri) {
+            Some(document) => (
+                document.text.clone(),
+                document.version,
+                document.language.clone(),
+            ),
+            None => return Ok(()),
+        };
+
+        let fixes = match language {
+            Some(language) => self.diagnose(&text, &language),
+            None => vec![],
+        };
+        let diagnostics: Vec<&Diagnostic> = fixes.iter().map(|fix| &fix.diagnostic).collect();
+        let params = json!({ "uri": uri, "version": version, "diagnostics": diagnostics });
+
+        {
+            let mut documents = self.documents.lock();
+            match documents.get_mut(uri) {
+                Some(document) if document.version == version => document.fixes = fixes,
+                _ => return Ok(()),
+            }
+        }
+
+        send(
+            &self.output,
+            &notification("textDocument/publishDiagnostics", params),
+        )
+    }
+
+    fn diagnose(&self, text: &str, language: &str) -> Vec<Fix> {
+        let access = self.access.lock().clone();
+        if let Err(e) = access.limit_by_ip(&self.state) {
+            log_error(language, &e);
+            return vec![];
+        }
+
+        // Like a batch item, the check of every service that has the language is counted
+        let state = &self.state;
+        let limits = &state.config.limits;
+        let functions = &state.language_functions;
+        let speller_allowed = limits.check_text("speller", text).and_then(|_| {
+            if functions.spelling_suggestions.has_language(language) {
+                access.count(state, "speller", language)
+            } else {
+                Ok(())
+            }
+        });
+        let grammar_allowed = limits.check_text("grammar", text).and_then(|_| {
+            if functions.grammar_suggestions.has_language(language) {
+                access.count(state, "grammar", language)
+            } else {
+                Ok(())
+            }
+        });
+
+        let (sender, receiver) = mpsc::channel();
+        let state = Arc::clone(&self.state);
+        let owned_text = text.to_owned();
+        let owned_language = language.to_owned();
+
+        // The checks are started on the arbiter and their results are sent back to this thread
+        self.arbiter.exec_fn(move || {
+            let check = check_text(
+                &state,
+                &owned_text,
+                &owned_language,
+                speller_allowed,
+                grammar_allowed,
+            );
+            Arbiter::spawn(check.map(move |results| {
+                // Nobody is waiting anymore if the checker has stopped
+                let _ = sender.send(results);
+            }));
+        });
+
+        // The sender is dropped without sending if the system stops first
+        let (speller, grammar) = match receiver.recv() {
+            Ok(results) => results,
+            Err(_) => return vec![],
+        };
+
+        fixes(text, language, speller, grammar)
+    }
+}
+
+type CheckResults = (
+    Result<SpellerResponse, ApiError>,
+    Result<GramcheckResponse, ApiError>,
+);
+
+/// Checks the spelling and grammar of the text with the services that are allowed to, which has
+/// to be done on an arbiter
+fn check_text(
+    state: &State,
+    text: &str,
+    language: &str,
+    speller_allowed: Result<(), ApiError>,
+    grammar_allowed: Result<(), ApiError>,
+) -> Box<dyn Future<Item = CheckResults, Error = ()>> {
+    let language_functions = &state.language_functions;
+
+    let speller: Box<dyn Future<Item = SpellerResponse, Error = ApiError>> = match speller_allowed {
+        Ok(()) => language_functions.spelling_suggestions.suggestions(
+            SpellerRequest {
+                text: text.to_owned(),
+                only_misspelled: true,
+                ..Default::default()
+            },
+            language,
+        ),
+        Err(e) => Box::new(future::err(e)),
+    };
+
+    let grammar: Box<dyn Future<Item = GramcheckResponse, Error = ApiError>> = match grammar_allowed
+    {
+        Ok(()) => language_functions.grammar_suggestions.suggestions(
+            GramcheckRequest {
+                text: text.to_owned(),
+                ignore_tags: vec![],
+                only_tags: None,
+                pipeline: None,
+                ui_language: None,
+                tag_filter: None,
+            },
+            language,
+        ),
+        Err(e) => Box::new(future::err(e)),
+    };
+
+    Box::new(speller.then(ok::<_, ()>).join(grammar.then(ok::<_, ()>)))
+}
+
+/// Turns the results into fixes, leaving out spelling errors the grammar checker reported too
+fn fixes(
+    text: &str,
+    language: &str,
+    speller: Result<SpellerResponse, ApiError>,
+    grammar: Result<GramcheckResponse, ApiError>,
+) -> Vec<Fix> {
+    let positions = Positions::new(text);
+    let mut fixes = vec![];
+
+    match grammar {
+        Ok(grammar) => fixes.extend(grammar.errs.into_iter().map(|err| {
+            let message = if err.description.is_empty() {
+                err.title
+            } else {
+                err.description
+            };
+
+            Fix {
+                diagnostic: Diagnostic {
+                    range: positions.range(err.start_index, err.end_index),
+                    severity: SEVERITY_WARNING,
+                    code: err.error_code,
+                    source: SOURCE.to_owned(),
+                    message,
+                },
+                suggestions: err.suggestions,
+            }
+        })),
+        Err(e) => log_error(language, &e),
+    }
+
+    match speller {
+        Ok(speller) => {
+            for result in speller.results.into_iter().filter(|r| !r.is_correct) {
+                let range = positions.range(result.start_char, result.end_char);
+                if fixes.iter().any(|fix| fix.diagnostic.range == range) {
+                    continue;
+                }
+
+                fixes.push(Fix {
+                    diagnostic: Diagnostic {
+                        range,
+                        severity: SEVERITY_INFORMATION,
+                        code: "typo".to_owned(),
+                        source: SOURCE.to_owned(),
+                        message: format!("Unknown word \"{}\"", result.word),
+                    },
+                    suggestions: result
+                        .suggestions
+                        .into_iter()
+                        .map(|suggestion| suggestion.value.to_string())
+                        .collect(),
+                });
+            }
+        }
+        Err(e) => log_error(language, &e),
+    }
+
+    fixes
+}
+
+fn log_error(language: &str, error: &ApiError) {
+    match error {
+        // Languages only having a speller or grammar checker are common
+        ApiError::LanguageNotFound(_) => debug!("{}", error),
+        _ => warn!("Checking a document in {} failed: {}", language, error),
+    }
+}
+
+/// Maps code point offsets within a text to positions
+struct Positions {
+    positions: Vec<Position>,
+}
+
+impl Positions {
+    fn new(text: &str) -> Self {
+        let mut positions = Vec::with_capacity(text.len() + 1);
+        let mut position = Position {
+            line: 0,
+            character: 0,
+        };
+
+        for c in text.chars() {
+            positions.push(position);
+
+            if c == '\n' {
+                position.line += 1;
+                position.character = 0;
+            } else {
+                position.character += c.len_utf16() as u32;
+            }
+        }
+        positions.push(position);
+
+        Positions { positions }
+    }
+
+    fn position(&self, offset: u32) -> Position {
+        let index = (offset as usize).min(self.positions.len() - 1);
+        self.positions[index]
+    }
+
+    fn range(&self, start: u32, end: u32) -> Range {
+        Range {
+            start: self.position(start),
+            end: self.position(end),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use futures::sync::oneshot;
+
+    use super::*;
+    use crate::config::{ApiKeySettings, AuthSettings, TomlConfig};
+    use crate::language::speller::SpellerResult;
+    use crate::server::state::test_support::{missing_functions, test_state as test_state_with};
+    use crate::server::state::{LanguageFunctions, LanguageSuggestions};
+    use crate::server::status::LanguageStatus;
+
+    /// Reports the first word of every text as misspelled
+    struct FakeSpeller;
+
+    impl LanguageSuggestions for FakeSpeller {
+        type Request = SpellerRequest;
+        type Response = SpellerResponse;
+
+        fn suggestions(
+            &self,
+            message: Self::Request,
+            _language: &str,
+        ) -> Box<dyn Future<Item = Self::Response, Error = ApiError>> {
+            let (sender, receiver) = oneshot::channel();
+
+            // Like the executors' mailboxes, spawning only works from within the actix system
+            Arbiter::spawn(future::lazy(move || {
+                let word = message.text.split_whitespace().next().unwrap_or("");
+                let length = word.chars().count() as u32;
+
+                let _ = sender.send(SpellerResponse {
+                    text: message.text.clone(),
+                    results: vec![SpellerResult {
+                        word: word.to_owned(),
+                        is_correct: false,
+                        suggestions: vec![],
+                        start_byte: 0,
+                        end_byte: word.len() as u32,
+                        start_utf16: 0,
+                        end_utf16: length,
+                        start_char: 0,
+                        end_char: length,
+                    }],
+                });
+                Ok(())
+            }));
+
+            Box::new(receiver.map_err(|_| ApiError::Internal("Check canceled".to_owned())))
+        }
+
+        fn add(&self, _: &str, _: &str) -> Box<dyn Future<Item = (), Error = ApiError>> {
+            Box::new(ok(()))
+        }
+
+        fn remove(&self, _: &str) -> Box<dyn Future<Item = (), Error = ApiError>> {
+            Box::new(ok(()))
+        }
+
+        fn has_language(&self, _: &str) -> bool {
+            false
+        }
+
+        fn status(&self) -> Vec<LanguageStatus> {
+            vec![]
+        }
+    }
+
+    fn test_state(auth: AuthSettings) -> State {
+        let language_functions = LanguageFunctions {
+            spelling_suggestions: Box::new(FakeSpeller),
+            ..missing_functions()
+        };
+
+        test_state_with(
+            TomlConfig {
+                auth,
+                ..Default::default()
+            },
+            language_functions,
+        )
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn start_system() -> System {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            System::run(move || {
+                let _ = sender.send(System::current());
+            })
+        });
+        receiver.recv().unwrap()
+    }
+
+    /// The messages written to the client, with their results and errors
+    fn replies(output: &SharedOutput) -> Vec<Value> {
+        let written = String::from_utf8(output.0.lock().clone()).unwrap();
+        written
+            .split("Content-Length: ")
+            .filter_map(|message| message.splitn(2, "\r\n\r\n").nth(1))
+            .map(|content| serde_json::from_str(content).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_serve() {
+        let system = start_system();
+
+        let mut input = vec![];
+        let messages = vec![
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "initialize",
+                "params": { "initializationOptions": { "language": "se" } },
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {
+                    "textDocument": {
+                        "uri": "file:///test.txt",
+                        "languageId": "plaintext",
+                        "version": 1,
+                        "text": "Buorre beaivi",
+                    },
+                },
+            }),
+        ];
+        for message in &messages {
+            write_message(&mut input, message).unwrap();
+        }
+
+        let output = SharedOutput::default();
+        serve(
+            test_state(AuthSettings::default()),
+            system.arbiter().clone(),
+            Access::default(),
+            Cursor::new(input),
+            Box::new(output.clone()),
+        )
+        .unwrap();
+
+        // The document is checked in the background and may still be in progress
+        let mut diagnostics = None;
+        for _ in 0..100 {
+            let replies = replies(&output);
+
+            assert_eq!(replies[0]["id"], 1);
+            diagnostics = replies
+                .into_iter()
+                .find(|message| message["method"] == "textDocument/publishDiagnostics")
+                .map(|message| message["params"].clone());
+
+            if diagnostics.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        system.stop();
+
+        let diagnostics = diagnostics.expect("diagnostics were published");
+        assert_eq!(diagnostics["uri"], "file:///test.txt");
+        assert_eq!(diagnostics["diagnostics"][0]["code"], "typo");
+        assert_eq!(
+            diagnostics["diagnostics"][0]["range"]["end"],
+            json!({ "line": 0, "character": 6 })
+        );
+    }
+
+    #[test]
+    fn test_serve_with_api_key() {
+        let system = start_system();
+
+        let mut keys = std::collections::HashMap::new();
+        keys.insert(
+            "editor".to_owned(),
+            ApiKeySettings {
+                key: "secret".to_owned(),
+                languages: vec![],
+                services: vec![],
+                requests_per_minute: None,
+                daily_quota: None,
+            },
+        );
+        let state = test_state(AuthSettings {
+            enabled: true,
+            keys,
+        });
+
+        let initialize = |id, options| {
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "initialize",
+                "params": { "initializationOptions": options },
+            })
+        };
+        let code_actions = json!({
+            "jsonrpc": "2.0",
+            "id": 4,
+            "method": "textDocument/codeAction",
+            "params": {
+                "textDocument": { "uri": "file:///test.txt" },
+                "range": {
+                    "start": { "line": 0, "character": 0 },
+                    "end": { "line": 0, "character": 0 },
+                },
+            },
+        });
+
+        let mut input = vec![];
+        for message in &[
+            initialize(1, json!({ "language": "se" })),
+            initialize(2, json!({ "language": "se", "key": "wrong" })),
+            code_actions.clone(),
+            initialize(3, json!({ "language": "se", "key": "secret" })),
+            code_actions,
+        ] {
+            write_message(&mut input, message).unwrap();
+        }
+
+        let output = SharedOutput::default();
+        let access = Access {
+            key_required: true,
+            client: None,
+            client_ip: None,
+        };
+        serve(
+            state,
+            system.arbiter().clone(),
+            access,
+            Cursor::new(input),
+            Box::new(output.clone()),
+        )
+        .unwrap();
+        system.stop();
+
+        // Nothing but initializing works until a valid key has been passed
+        let replies = replies(&output);
+        let ids: Vec<&Value> = replies.iter().map(|reply| &reply["id"]).collect();
+        assert_eq!(ids, vec![1, 2, 4, 3, 4]);
+        let failed: Vec<bool> = replies
+            .iter()
+            .map(|reply| reply.get("error").is_some())
+            .collect();
+        assert_eq!(failed, vec![true, true, true, false, false]);
+    }
+
+    #[test]
+    fn test_document_language() {
+        let settings = LspSettings {
+            language: Some("se".to_owned()),
+        };
+
+        assert_eq!(
+            document_language("# divvun-language: sma\n\nBuerie biejjie", &settings),
+            Some("sma".to_owned())
+        );
+        assert_eq!(
+            document_language("Buorre beaivi", &settings),
+            Some("se".to_owned())
+        );
+        assert_eq!(
+            document_language("Buorre beaivi", &LspSettings::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_positions() {
+        let positions = Positions::new("Čáhci\n𝒜 sámegiella");
+
+        assert_eq!(
+            positions.position(3),
+            Position {
+                line: 0,
+                character: 3
+            }
+        );
+        assert_eq!(
+            positions.position(6),
+            Position {
+                line: 1,
+                character: 0
+            }
+        );
+        // Characters outside the basic plane take two UTF-16 code units
+        assert_eq!(
+            positions.position(8),
+            Position {
+                line: 1,
+                character: 3
+            }
+        );
+        assert_eq!(
+            positions.position(100),
+            Position {
+                line: 1,
+                character: 13
+            }
+        );
+    }
+}
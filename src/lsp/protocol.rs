@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const CONTENT_LENGTH_HEADER: &str = "content-length";
+
+/// Largest message accepted from a client, which holds a whole document on every change
+pub const MAX_MESSAGE_BYTES: usize = 8 * 1024 * 1024;
+
+/// Largest header line accepted from a client
+const MAX_HEADER_BYTES: u64 = 1024;
+
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const REQUEST_FAILED: i64 = -32803;
+
+pub const SEVERITY_WARNING: u8 = 2;
+pub const SEVERITY_INFORMATION: u8 = 3;
+
+/// Whole documents are sent on every change
+pub const TEXT_DOCUMENT_SYNC_FULL: u8 = 1;
+
+/// A JSON-RPC request, or a notification if it has no id
+#[derive(Deserialize, Debug)]
+pub struct Message {
+    pub id: Option<Value>,
+    pub method: Option<String>,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ResponseError {
+    pub code: i64,
+    pub message: String,
+}
+
+/// Reads the next message, returning `None` once the input is closed. Messages larger than
+/// `MAX_MESSAGE_BYTES` are rejected before they are read.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Message>> {
+    let mut content_length = None;
+
+    loop {
+        let mut line = String::new();
+        if reader
+            .by_ref()
+            .take(MAX_HEADER_BYTES)
+            .read_line(&mut line)?
+            == 0
+        {
+            return Ok(None);
+        }
+        if !line.ends_with('\n') && line.len() as u64 == MAX_HEADER_BYTES {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Header line exceeds the size limit",
+            ));
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+
+        if name == CONTENT_LENGTH_HEADER {
+            content_length = Some(value.parse::<usize>().map_err(|e| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid Content-Length: {}", e),
+                )
+            })?);
+        }
+    }
+
+    let content_length = content_length.ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            "Message without a Content-Length header",
+        )
+    })?;
+
+    if content_length > MAX_MESSAGE_BYTES {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Message of {} bytes exceeds the limit of {} bytes",
+                content_length, MAX_MESSAGE_BYTES
+            ),
+        ));
+    }
+
+    let mut content = vec![0; content_length];
+    reader.read_exact(&mut content)?;
+
+    serde_json::from_slice(&content)
+        .map(Some)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+pub fn write_message<W: Write + ?Sized>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let content = serde_json::to_string(message)?;
+
+    write!(
+        writer,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )?;
+    writer.flush()
+}
+
+pub fn response(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+pub fn error_response(id: Value, error: ResponseError) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": error })
+}
+
+pub fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+/// A position in a document, with the character counted in UTF-16 code units
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: u32,
+    pub character: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Range {
+    pub fn overlaps(&self, other: &Range) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub range: Range,
+    pub severity: u8,
+    pub code: String,
+    pub source: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TextEdit {
+    pub range: Range,
+    #[serde(rename = "newText")]
+    pub new_text: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct WorkspaceEdit {
+    pub changes: HashMap<String, Vec<TextEdit>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CodeAction {
+    pub title: String,
+    pub kind: String,
+    pub diagnostics: Vec<Diagnostic>,
+    pub edit: WorkspaceEdit,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TextDocumentIdentifier {
+    pub uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TextDocumentItem {
+    pub uri: String,
+    pub version: i64,
+    pub text: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VersionedTextDocumentIdentifier {
+    pub uri: String,
+    pub version: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TextDocumentContentChangeEvent {
+    pub text: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DidOpenTextDocumentParams {
+    #[serde(rename = "textDocument")]
+    pub text_document: TextDocumentItem,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DidChangeTextDocumentParams {
+    #[serde(rename = "textDocument")]
+    pub text_document: VersionedTextDocumentIdentifier,
+    #[serde(rename = "contentChanges")]
+    pub content_changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DidCloseTextDocumentParams {
+    #[serde(rename = "textDocument")]
+    pub text_document: TextDocumentIdentifier,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CodeActionParams {
+    #[serde(rename = "textDocument")]
+    pub text_document: TextDocumentIdentifier,
+    pub range: Range,
+}
+
+/// The server's settings, sent as `initializationOptions` or as the `divvun` section of
+/// `workspace/didChangeConfiguration`
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct LspSettings {
+    /// Language of documents without a language hint
+    pub language: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_write_message() {
+        let mut buffer = vec![];
+        write_message(&mut buffer, &notification("initialized", json!({}))).unwrap();
+        write_message(&mut buffer, &response(json!(1), Value::Null)).unwrap();
+
+        let mut reader = Cursor::new(buffer);
+
+        let message = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(message.method.as_deref(), Some("initialized"));
+        assert!(message.id.is_none());
+
+        let message = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(message.id, Some(json!(1)));
+
+        assert!(read_message(&mut reader).unwrap().is_none());
+
+        let mut reader = Cursor::new(format!("Content-Length: {}\r\n\r\n", MAX_MESSAGE_BYTES + 1));
+        let error = read_message(&mut reader).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_range_overlaps() {
+        let range = |start, end| Range {
+            start: Position {
+                line: 0,
+                character: start,
+            },
+            end: Position {
+                line: 0,
+                character: end,
+            },
+        };
+
+        assert!(range(2, 5).overlaps(&range(4, 4)));
+        assert!(range(2, 5).overlaps(&range(5, 8)));
+        assert!(!range(2, 5).overlaps(&range(6, 8)));
+    }
+}
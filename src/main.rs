@@ -1,30 +1,72 @@
-use divvun_api::init::{init_config, init_system};
+use divvun_api::init::{init_cli, init_config, init_lsp, init_system};
 
 use std::env;
-use std::path::PathBuf;
+use std::process;
 
-use directories::ProjectDirs;
 use divvun_api::config::Config;
+use divvun_api::lsp::Transport;
+use env_logger::Env;
+use log::{error, LevelFilter};
+
+/// The language server doesn't bind the HTTP address, but the config requires one
+const UNUSED_ADDR: &str = "127.0.0.1:0";
+
+/// Environment variable with log filters overriding the configured log level
+const LOG_FILTERS_ENV: &str = "RUST_LOG";
 
 fn main() {
-    env::set_var("RUST_LOG", "info");
-    env_logger::init();
-
-    let toml_config = init_config();
-
-    let config = Config {
-        addr: toml_config.addr,
-        data_file_dir: match toml_config.data_file_dir {
-            Some(dir) => dir,
-            None => match ProjectDirs::from("no", "uit", "api-giellalt") {
-                Some(v) => v.data_dir().to_owned(),
-                None => PathBuf::from("./"),
-            },
-        },
-        watcher_interval_ms: 1000,
+    // Filters set in RUST_LOG are used as they are. Otherwise everything is let through by the
+    // logger and the level is limited to the configured one once the config is loaded. It
+    // writes to stderr, so stdout is free for the language server.
+    let filters_from_env = env::var_os(LOG_FILTERS_ENV).is_some();
+    env_logger::Builder::from_env(Env::new().filter_or(LOG_FILTERS_ENV, "trace")).init();
+    if !filters_from_env {
+        log::set_max_level(LevelFilter::Info);
+    }
+
+    let matches = init_cli();
+    let lsp = matches.subcommand_matches("lsp");
+
+    let config = match init_config(&matches).and_then(|mut toml_config| {
+        if lsp.is_some() && toml_config.addr.is_none() {
+            toml_config.addr = Some(UNUSED_ADDR.to_owned());
+        }
+        Config::from_toml(toml_config)
+    }) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            process::exit(1);
+        }
     };
 
-    let (_app, system) = init_system(&config);
+    if !filters_from_env {
+        log::set_max_level(config.log_level);
+    }
+
+    let system = match lsp {
+        Some(lsp) => {
+            let transport = match lsp.value_of("tcp") {
+                Some(addr) => Transport::Tcp(addr.to_owned()),
+                None => Transport::Stdio,
+            };
+
+            match init_lsp(&config, transport) {
+                Ok(system) => system,
+                Err(e) => {
+                    error!("Failed to start the language server: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => match init_system(&config) {
+            Ok((_app, system)) => system,
+            Err(e) => {
+                error!("Failed to start the server on {}: {}", config.addr, e);
+                process::exit(1);
+            }
+        },
+    };
 
     system.run().unwrap();
 }
@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::{web, Error, HttpResponse};
+use futures::future::Future;
+use lazy_static::lazy_static;
+use log::error;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder,
+    HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+use crate::language::data_files::DataFileType;
+use crate::server::state::State;
+
+/// Routes that get their own label, everything else is counted as `other`
+const ROUTES: &[&str] = &[
+    "speller",
+    "grammar",
+    "hyphenation",
+    "preferences",
+    "languages",
+    "batch",
+    "live",
+    "graphql",
+    "graphiql",
+    "health",
+    "ready",
+    "status",
+    "metrics",
+];
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "divvun_api_http_requests_total",
+        "Number of HTTP requests by route, language and status",
+        &["route", "language", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "divvun_api_http_request_duration_seconds",
+        "HTTP request latency by route and language",
+        &["route", "language"]
+    )
+    .unwrap();
+    pub static ref WORDS_PROCESSED: IntCounterVec = register_int_counter_vec!(
+        "divvun_api_words_processed_total",
+        "Number of words processed by service and language",
+        &["service", "language"]
+    )
+    .unwrap();
+    pub static ref SPELLER_SUGGESTION_DURATION: HistogramVec = register_histogram_vec!(
+        "divvun_api_speller_suggestion_duration_seconds",
+        "Time spent generating suggestions for a single word",
+        &["language"]
+    )
+    .unwrap();
+    pub static ref GRAMCHECKER_RESTARTS: IntCounterVec = register_int_counter_vec!(
+        "divvun_api_gramchecker_restarts_total",
+        "Number of divvun-checker restarts by language",
+        &["language"]
+    )
+    .unwrap();
+    pub static ref WATCHER_EVENTS: IntCounterVec = register_int_counter_vec!(
+        "divvun_api_watcher_events_total",
+        "Number of data file changes handled by the watcher",
+        &["event", "data_type"]
+    )
+    .unwrap();
+    pub static ref LOADED_LANGUAGES: IntGaugeVec = register_int_gauge_vec!(
+        "divvun_api_loaded_languages",
+        "Number of loaded languages by data file type",
+        &["data_type"]
+    )
+    .unwrap();
+}
+
+pub fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn route_label(path: &str) -> &'static str {
+    let segment = path.trim_start_matches('/').split('/').next().unwrap_or("");
+
+    ROUTES
+        .iter()
+        .find(|route| **route == segment)
+        .cloned()
+        .unwrap_or("other")
+}
+
+/// The language of a request as labeled. Only loaded languages are labeled as themselves, to
+/// keep the number of series bounded whatever clients put in the path.
+fn language_label<'a>(state: &State, language: Option<&'a str>) -> &'a str {
+    let language = match language {
+        Some(language) => language,
+        None => return "",
+    };
+
+    let language_functions = &state.language_functions;
+    if language_functions
+        .spelling_suggestions
+        .has_language(language)
+        || language_functions
+            .grammar_suggestions
+            .has_language(language)
+        || language_functions
+            .hyphenation_suggestions
+            .has_language(language)
+    {
+        language
+    } else {
+        "unknown"
+    }
+}
+
+/// Middleware function recording the count and latency of every request
+pub fn record_request<S, B>(
+    state: &State,
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Item = ServiceResponse<B>, Error = Error>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let start = Instant::now();
+    let route = route_label(req.path());
+    let state = state.clone();
+
+    srv.call(req).map(move |res| {
+        let language = language_label(&state, res.request().match_info().get("languageCode"));
+
+        HTTP_REQUESTS
+            .with_label_values(&[route, language, res.status().as_str()])
+            .inc();
+        HTTP_REQUEST_DURATION
+            .with_label_values(&[route, language])
+            .observe(seconds(start.elapsed()));
+
+        res
+    })
+}
+
+pub fn metrics_handler(state: web::Data<State>) -> HttpResponse {
+    let language_functions = &state.language_functions;
+
+    for (data_type, statuses) in &[
+        (
+            DataFileType::Spelling,
+            language_functions.spelling_suggestions.status(),
+        ),
+        (
+            DataFileType::Grammar,
+            language_functions.grammar_suggestions.status(),
+        ),
+        (
+            DataFileType::Hyphenation,
+            language_functions.hyphenation_suggestions.status(),
+        ),
+    ] {
+        // Grammar checkers are listed once per pipeline
+        let loaded = statuses
+            .iter()
+            .map(|status| &status.language)
+            .collect::<HashSet<_>>()
+            .len();
+
+        LOADED_LANGUAGES
+            .with_label_values(&[data_type.as_dir()])
+            .set(loaded as i64);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!("Failed to encode metrics: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer)
+}
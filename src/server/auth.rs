@@ -0,0 +1,347 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, Either, Future};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+
+use crate::config::{ApiKeySettings, AuthSettings};
+use crate::error::ApiError;
+use crate::server::rate_limit::TokenBucket;
+use crate::server::state::State;
+use crate::server::status::unix_seconds;
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A client identified by its API key
+#[derive(Debug)]
+pub struct Client {
+    pub name: String,
+    pub settings: ApiKeySettings,
+}
+
+impl Client {
+    /// Checks whether the client's key may be used for the service and language
+    pub fn authorize(&self, service: &str, language: &str) -> Result<(), ApiError> {
+        let settings = &self.settings;
+
+        if !settings.services.is_empty() && !settings.services.iter().any(|s| s == service) {
+            return Err(ApiError::Forbidden(format!(
+                "The API key is not allowed to use the {} service",
+                service
+            )));
+        }
+
+        if !settings.languages.is_empty() && !settings.languages.iter().any(|l| l == language) {
+            return Err(ApiError::Forbidden(format!(
+                "The API key is not allowed to use language {}",
+                language
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether the client's key may be used for the status endpoint, which shows the
+    /// server's internals and so is only open to keys naming it in their services
+    pub fn authorize_status(&self) -> Result<(), ApiError> {
+        if self.settings.services.iter().any(|s| s == "status") {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden(
+                "The API key is not allowed to see the status".to_owned(),
+            ))
+        }
+    }
+}
+
+struct Usage {
+    bucket: Option<TokenBucket>,
+    /// Days since the Unix epoch
+    day: u64,
+    requests_today: u64,
+}
+
+pub struct Authenticator {
+    enabled: bool,
+    clients: HashMap<String, Arc<Client>>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl Authenticator {
+    pub fn new(settings: &AuthSettings) -> Self {
+        let clients = settings
+            .keys
+            .iter()
+            .map(|(name, key)| {
+                (
+                    key.key.clone(),
+                    Arc::new(Client {
+                        name: name.clone(),
+                        settings: key.clone(),
+                    }),
+                )
+            })
+            .collect();
+
+        Authenticator {
+            enabled: settings.enabled,
+            clients,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Identifies the client by its key. Returns `None` if authentication is disabled. The
+    /// request is counted separately, once the client is known to be allowed to make it.
+    pub fn authenticate(&self, key: Option<&str>) -> Result<Option<Arc<Client>>, ApiError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        let key = key.ok_or_else(|| {
+            ApiError::Unauthorized(format!(
+                "An API key is required, pass it in the {} header",
+                API_KEY_HEADER
+            ))
+        })?;
+
+        let client = self
+            .clients
+            .get(key)
+            .ok_or_else(|| ApiError::Unauthorized("Invalid API key".to_owned()))?;
+
+        Ok(Some(Arc::clone(client)))
+    }
+
+    /// Counts a request of the client against its rate limit and daily quota
+    pub fn count(&self, client: &Client) -> Result<(), ApiError> {
+        self.count_request(client, Instant::now(), SystemTime::now())
+    }
+
+    fn count_request(
+        &self,
+        client: &Client,
+        now: Instant,
+        time: SystemTime,
+    ) -> Result<(), ApiError> {
+        let settings = &client.settings;
+        let today = unix_seconds(time) / SECONDS_PER_DAY;
+
+        let mut usage = self.usage.lock();
+        let usage = usage.entry(client.name.clone()).or_insert_with(|| Usage {
+            bucket: settings
+                .requests_per_minute
+                .map(|requests| TokenBucket::per_minute(requests, now)),
+            day: today,
+            requests_today: 0,
+        });
+
+        if usage.day != today {
+            usage.day = today;
+            usage.requests_today = 0;
+        }
+
+        if let Some(quota) = settings.daily_quota {
+            if usage.requests_today >= quota {
+                let until_tomorrow = SECONDS_PER_DAY - unix_seconds(time) % SECONDS_PER_DAY;
+
+                return Err(ApiError::TooManyRequests(
+                    format!("The daily quota of {} requests has been used up", quota),
+                    Some(Duration::from_secs(until_tomorrow)),
+                ));
+            }
+        }
+
+        if let Some(bucket) = usage.bucket.as_mut() {
+            if let Err(wait) = bucket.take(now) {
+                return Err(ApiError::TooManyRequests(
+                    format!(
+                        "Rate limit of {} requests per minute exceeded, retry in {} ms",
+                        settings.requests_per_minute.unwrap_or(0),
+                        wait.as_millis()
+                    ),
+                    Some(wait),
+                ));
+            }
+        }
+
+        usage.requests_today += 1;
+
+        Ok(())
+    }
+}
+
+fn request_key(req: &ServiceRequest) -> Option<String> {
+    let headers = req.headers();
+
+    if let Some(key) = headers.get(API_KEY_HEADER) {
+        return key.to_str().ok().map(str::to_owned);
+    }
+
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with("Bearer "))
+        .map(|value| value["Bearer ".len()..].trim().to_owned())
+}
+
+/// The service and language a request is for, taken from the path. GraphQL and batch requests
+/// name them in the body instead, and live sessions use several services, so they are checked
+/// further down.
+fn requested_service(path: &str) -> Option<(&'static str, Option<&str>)> {
+    let mut segments = path.trim_start_matches('/').split('/');
+
+    match segments.next()? {
+        "speller" => Some(("speller", segments.next())),
+        "grammar" => Some(("grammar", segments.next())),
+        "hyphenation" => Some(("hyphenation", segments.next())),
+        "preferences" => Some(("grammar", segments.nth(1))),
+        "graphql" => Some(("graphql", None)),
+        "batch" => Some(("batch", None)),
+        "live" => Some(("live", None)),
+        "status" => Some(("status", None)),
+        _ => None,
+    }
+}
+
+/// Middleware function authenticating requests to the language services. The client is
+/// stored in the request extensions for the handlers further down.
+pub fn authenticate<S, B>(
+    state: &State,
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Item = ServiceResponse<B>, Error = Error>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let (service, language) = match requested_service(req.path()) {
+        Some((service, language)) => (service, language.map(str::to_owned)),
+        None => return Either::B(srv.call(req)),
+    };
+
+    let key = request_key(&req);
+
+    // Browsers can't set headers on a WebSocket, so a live session may send its key in its
+    // first message instead
+    if key.is_none() && service == "live" {
+        return Either::B(srv.call(req));
+    }
+
+    let client = match state.authenticator.authenticate(key.as_deref()) {
+        Ok(client) => client,
+        Err(e) => return Either::A(ok(req.error_response(e))),
+    };
+
+    if let Some(client) = client {
+        // Forbidden requests don't count against the client's limits. GraphQL and batch
+        // requests count every check they make instead of themselves.
+        let allowed = match (service, language) {
+            ("graphql", _) | ("batch", _) => Ok(()),
+            ("status", _) => client
+                .authorize_status()
+                .and_then(|_| state.authenticator.count(&client)),
+            (service, Some(language)) => client
+                .authorize(service, &language)
+                .and_then(|_| state.authenticator.count(&client)),
+            (_, None) => state.authenticator.count(&client),
+        };
+
+        if let Err(e) = allowed {
+            return Either::A(ok(req.error_response(e)));
+        }
+
+        req.extensions_mut().insert(client);
+    }
+
+    Either::B(srv.call(req))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn authenticator(requests_per_minute: Option<u32>, daily_quota: Option<u64>) -> Authenticator {
+        let mut keys = std::collections::HashMap::new();
+        keys.insert(
+            "app".to_owned(),
+            ApiKeySettings {
+                key: "secret".to_owned(),
+                languages: vec!["se".to_owned()],
+                services: vec!["speller".to_owned()],
+                requests_per_minute,
+                daily_quota,
+            },
+        );
+
+        Authenticator::new(&AuthSettings {
+            enabled: true,
+            keys,
+        })
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let auth = authenticator(None, None);
+
+        assert_eq!(auth.authenticate(None).unwrap_err().code(), "unauthorized");
+        assert_eq!(
+            auth.authenticate(Some("wrong")).unwrap_err().code(),
+            "unauthorized"
+        );
+
+        let client = auth.authenticate(Some("secret")).unwrap().unwrap();
+        assert_eq!(client.name, "app");
+        assert!(client.authorize("speller", "se").is_ok());
+        assert_eq!(
+            client.authorize("grammar", "se").unwrap_err().code(),
+            "forbidden"
+        );
+        assert_eq!(
+            client.authorize("speller", "sma").unwrap_err().code(),
+            "forbidden"
+        );
+        assert_eq!(client.authorize_status().unwrap_err().code(), "forbidden");
+
+        let disabled = Authenticator::new(&AuthSettings::default());
+        assert!(disabled.authenticate(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_limits() {
+        let auth = authenticator(Some(1), Some(2));
+        let client = Arc::clone(auth.clients.get("secret").unwrap());
+        let now = Instant::now();
+        let day = SystemTime::UNIX_EPOCH + Duration::from_secs(SECONDS_PER_DAY * 100);
+
+        assert!(auth.count_request(&client, now, day).is_ok());
+        assert_eq!(
+            auth.count_request(&client, now, day).unwrap_err().code(),
+            "too_many_requests"
+        );
+
+        let later = now + Duration::from_secs(60);
+        assert!(auth.count_request(&client, later, day).is_ok());
+
+        // The quota is used up until the next day
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(
+            auth.count_request(&client, much_later, day)
+                .unwrap_err()
+                .code(),
+            "too_many_requests"
+        );
+
+        let next_day = day + Duration::from_secs(SECONDS_PER_DAY);
+        assert!(auth.count_request(&client, much_later, next_day).is_ok());
+    }
+}
@@ -1,35 +1,52 @@
 use std::env;
+use std::io;
 
 use actix_cors::Cors;
 use actix_web::dev::Server;
+use actix_web::error::JsonPayloadError;
 use actix_web::{http::header, middleware, web, App, HttpServer};
 
+pub mod auth;
+pub mod rate_limit;
 pub mod state;
+pub mod status;
 
 use self::state::State;
-use crate::config::Config;
+use self::status::{health_handler, ready_handler, status_handler};
+use crate::config::{Config, CorsSettings};
+use crate::error::ApiError;
 use crate::graphql::handlers::{graphiql, graphql};
+use crate::metrics::{metrics_handler, record_request};
+
+use crate::language::live::live_handler;
 
 use crate::language::handlers::{
-    get_available_languages_handler, get_gramcheck_preferences_handler, gramchecker_handler,
-    hyphenation_handler, speller_handler,
+    batch_handler, get_available_languages_handler, get_gramcheck_preferences_handler,
+    gramchecker_handler, hyphenation_handler, speller_handler,
 };
 
-pub fn start_server(state: State, config: &Config) -> Server {
+pub fn start_server(state: State, config: &Config) -> io::Result<Server> {
     env::set_var("RUST_BACKTRACE", "1");
 
-    HttpServer::new(move || {
+    let cors_settings = config.cors.clone();
+    let json_body_bytes = config.limits.json_body_bytes;
+    let batch_body_bytes = config.limits.batch_body_bytes;
+
+    rate_limit::start_sweeper(&state);
+
+    let server = HttpServer::new(move || {
+        let auth_state = state.clone();
+        let rate_limit_state = state.clone();
+        let metrics_state = state.clone();
+
         App::new()
             .data(state.clone())
+            .data(json_config(json_body_bytes))
+            .wrap_fn(move |req, srv| auth::authenticate(&auth_state, req, srv))
+            .wrap_fn(move |req, srv| rate_limit::limit_by_ip(&rate_limit_state, req, srv))
+            .wrap_fn(move |req, srv| record_request(&metrics_state, req, srv))
             .wrap(middleware::Logger::default())
-            .wrap(
-                Cors::new()
-                    .send_wildcard()
-                    .allowed_methods(vec!["POST", "GET"])
-                    .allowed_headers(vec![header::ACCEPT])
-                    .allowed_header(header::CONTENT_TYPE)
-                    .max_age(3600),
-            )
+            .wrap(cors(&cors_settings))
             .service(web::resource("/graphiql").route(web::get().to(graphiql)))
             .service(web::resource("/graphql").route(web::post().to_async(graphql)))
             .service(
@@ -44,6 +61,12 @@ pub fn start_server(state: State, config: &Config) -> Server {
                 web::resource("/hyphenation/{languageCode}")
                     .route(web::post().to_async(hyphenation_handler)),
             )
+            .service(
+                web::resource("/batch")
+                    .data(json_config(batch_body_bytes))
+                    .route(web::post().to_async(batch_handler)),
+            )
+            .service(web::resource("/live/{languageCode}").route(web::get().to(live_handler)))
             .service(
                 web::resource("/preferences/grammar/{languageCode}")
                     .route(web::get().to_async(get_gramcheck_preferences_handler)),
@@ -51,9 +74,48 @@ pub fn start_server(state: State, config: &Config) -> Server {
             .service(
                 web::resource("/languages").route(web::get().to(get_available_languages_handler)),
             )
+            .service(web::resource("/health").route(web::get().to(health_handler)))
+            .service(web::resource("/ready").route(web::get().to(ready_handler)))
+            .service(web::resource("/status").route(web::get().to(status_handler)))
+            .service(web::resource("/metrics").route(web::get().to(metrics_handler)))
     })
-    .workers(4)
-    .bind(&config.addr)
-    .unwrap()
-    .start()
+    .workers(config.workers)
+    .bind(&config.addr)?
+    .start();
+
+    Ok(server)
+}
+
+fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(move |err, _req| match err {
+            JsonPayloadError::Overflow => ApiError::PayloadTooLarge(format!(
+                "The request body is larger than the {} bytes allowed",
+                limit
+            ))
+            .into(),
+            err => ApiError::InvalidInput(format!("Invalid request body: {}", err)).into(),
+        })
+}
+
+fn cors(settings: &CorsSettings) -> Cors {
+    let mut cors = Cors::new();
+
+    if settings.allows_any_origin() {
+        cors = cors.send_wildcard();
+    } else {
+        for origin in &settings.allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+    }
+
+    cors.allowed_methods(settings.allowed_methods.iter().map(String::as_str))
+        .allowed_headers(vec![header::ACCEPT])
+        .allowed_header(header::CONTENT_TYPE)
+        .allowed_header(header::ACCEPT_LANGUAGE)
+        // Browsers send the API key in one of these
+        .allowed_header(auth::API_KEY_HEADER)
+        .allowed_header(header::AUTHORIZATION)
+        .max_age(settings.max_age_secs)
 }
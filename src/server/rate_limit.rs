@@ -0,0 +1,355 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::HeaderMap;
+use actix_web::Error;
+use futures::future::{ok, Either, Future};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+
+use crate::config::RateLimitSettings;
+use crate::error::ApiError;
+use crate::server::state::State;
+
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+/// How often the clients that have been idle long enough are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most clients tracked at once. A new client beyond it makes an arbitrary one be forgotten,
+/// which at worst hands that client a fresh burst.
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// Routes that are never rate limited, so monitoring keeps working
+const UNLIMITED_ROUTES: &[&str] = &["health", "ready", "metrics"];
+
+/// Allows bursts of up to `capacity` requests, refilled continuously at `per_second`
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, per_second: f64, now: Instant) -> Self {
+        TokenBucket {
+            capacity: f64::from(capacity),
+            tokens: f64::from(capacity),
+            per_second,
+            updated: now,
+        }
+    }
+
+    pub fn per_minute(requests: u32, now: Instant) -> Self {
+        TokenBucket::new(requests, f64::from(requests) / 60.0, now)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Whether a token is available, without taking it
+    pub fn has_token(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    /// Takes a token, returning how long to wait for the next one if there is none
+    pub fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        if self.has_token(now) {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let missing = 1.0 - self.tokens;
+        Err(Duration::from_millis(
+            (missing / self.per_second * 1000.0).ceil() as u64,
+        ))
+    }
+
+    /// Whether the bucket is full, i.e. it can be forgotten without changing anything
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+pub struct IpRateLimiter {
+    settings: RateLimitSettings,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl IpRateLimiter {
+    pub fn new(settings: &RateLimitSettings) -> Self {
+        IpRateLimiter {
+            settings: settings.clone(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn new_bucket(&self, now: Instant) -> TokenBucket {
+        TokenBucket::new(
+            self.settings.burst,
+            f64::from(self.settings.requests_per_minute) / 60.0,
+            now,
+        )
+    }
+
+    /// Counts a request from the client against its limit
+    pub fn check(&self, client: IpAddr, now: Instant) -> Result<(), ApiError> {
+        if !self.settings.enabled {
+            return Ok(());
+        }
+
+        let key = client_key(client);
+        let mut buckets = self.buckets.lock();
+
+        let result = match buckets.get_mut(&key) {
+            Some(bucket) => bucket.take(now),
+            None => {
+                if buckets.len() >= MAX_TRACKED_CLIENTS {
+                    if let Some(forgotten) = buckets.keys().next().cloned() {
+                        buckets.remove(&forgotten);
+                    }
+                }
+
+                let mut bucket = self.new_bucket(now);
+                let result = bucket.take(now);
+                buckets.insert(key, bucket);
+                result
+            }
+        };
+
+        result.map_err(|wait| {
+            ApiError::TooManyRequests(
+                format!(
+                    "Too many requests from {}, retry in {} ms",
+                    client,
+                    wait.as_millis()
+                ),
+                Some(wait),
+            )
+        })
+    }
+
+    /// Forgets the clients whose buckets have filled up again
+    pub fn sweep(&self, now: Instant) {
+        self.buckets.lock().retain(|_, bucket| !bucket.is_full(now));
+    }
+
+    /// The address of the client. Behind a trusted proxy this is the last address in
+    /// `X-Forwarded-For` that isn't itself a trusted proxy.
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let trusted = &self.settings.trusted_proxies;
+
+        if !trusted.contains(&peer) {
+            return peer;
+        }
+
+        let forwarded_for = match forwarded_for {
+            Some(forwarded_for) => forwarded_for,
+            None => return peer,
+        };
+
+        let mut client = peer;
+        for address in forwarded_for.rsplit(',') {
+            match address.trim().parse::<IpAddr>() {
+                Ok(address) => {
+                    client = address;
+                    if !trusted.contains(&address) {
+                        break;
+                    }
+                }
+                // Anything before a malformed entry can't be trusted
+                Err(_) => break,
+            }
+        }
+
+        client
+    }
+
+    /// The address of the client of a request from the peer with the headers
+    pub fn request_client_ip(
+        &self,
+        peer: Option<SocketAddr>,
+        headers: &HeaderMap,
+    ) -> Option<IpAddr> {
+        let forwarded_for = headers
+            .get(FORWARDED_FOR_HEADER)
+            .and_then(|value| value.to_str().ok());
+
+        peer.map(|peer| self.client_ip(peer.ip(), forwarded_for))
+    }
+}
+
+/// The address clients are told apart by. An IPv6 host usually has a whole /64 network to pick
+/// addresses from, so it is limited as one client.
+fn client_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V6(address) => {
+            let segments = address.segments();
+            IpAddr::V6(Ipv6Addr::new(
+                segments[0],
+                segments[1],
+                segments[2],
+                segments[3],
+                0,
+                0,
+                0,
+                0,
+            ))
+        }
+        address => address,
+    }
+}
+
+/// Sweeps the rate limiter's idle clients in the background for as long as the state exists
+pub fn start_sweeper(state: &State) {
+    if !state.config.rate_limit.enabled {
+        return;
+    }
+
+    let state = Arc::downgrade(state);
+    thread::spawn(move || loop {
+        thread::sleep(SWEEP_INTERVAL);
+
+        match state.upgrade() {
+            Some(state) => state.rate_limiter.sweep(Instant::now()),
+            None => break,
+        }
+    });
+}
+
+/// Middleware function rate limiting requests by client IP address
+pub fn limit_by_ip<S, B>(
+    state: &State,
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Item = ServiceResponse<B>, Error = Error>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let route = req.path().trim_start_matches('/').split('/').next();
+    if route.map_or(false, |route| UNLIMITED_ROUTES.contains(&route)) {
+        return Either::B(srv.call(req));
+    }
+
+    let limiter = &state.rate_limiter;
+    let client = match limiter.request_client_ip(req.head().peer_addr, req.headers()) {
+        Some(client) => client,
+        None => return Either::B(srv.call(req)),
+    };
+
+    match limiter.check(client, Instant::now()) {
+        Ok(()) => Either::B(srv.call(req)),
+        Err(e) => Either::A(ok(req.error_response(e))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::per_minute(2, start);
+
+        assert!(bucket.take(start).is_ok());
+        assert!(bucket.take(start).is_ok());
+
+        let wait = bucket.take(start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(30));
+
+        assert!(bucket.take(start + Duration::from_secs(29)).is_err());
+        assert!(bucket.take(start + Duration::from_secs(30)).is_ok());
+        assert!(!bucket.is_full(start + Duration::from_secs(30)));
+        assert!(bucket.is_full(start + Duration::from_secs(90)));
+    }
+
+    fn limiter(trusted_proxies: &[&str]) -> IpRateLimiter {
+        IpRateLimiter::new(&RateLimitSettings {
+            enabled: true,
+            requests_per_minute: 60,
+            burst: 2,
+            trusted_proxies: trusted_proxies
+                .iter()
+                .map(|ip| ip.parse().unwrap())
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn test_ip_rate_limiter() {
+        let limiter = limiter(&[]);
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        assert!(limiter.check(client, now).is_ok());
+        assert!(limiter.check(client, now).is_ok());
+        assert_eq!(
+            limiter.check(client, now).unwrap_err().code(),
+            "too_many_requests"
+        );
+        assert!(limiter.check(other, now).is_ok());
+        assert!(limiter.check(client, now + Duration::from_secs(1)).is_ok());
+
+        // Full buckets are forgotten
+        limiter.sweep(now + Duration::from_secs(1));
+        assert_eq!(limiter.buckets.lock().len(), 1);
+        limiter.sweep(now + Duration::from_secs(10));
+        assert_eq!(limiter.buckets.lock().len(), 0);
+    }
+
+    #[test]
+    fn test_ipv6_networks() {
+        let limiter = limiter(&[]);
+        let now = Instant::now();
+        let client: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+        let neighbour: IpAddr = "2001:db8:0:1::2".parse().unwrap();
+        let other: IpAddr = "2001:db8:0:2::1".parse().unwrap();
+
+        assert!(limiter.check(client, now).is_ok());
+        assert!(limiter.check(neighbour, now).is_ok());
+        match limiter.check(client, now) {
+            Err(ApiError::TooManyRequests(_, Some(wait))) => {
+                assert_eq!(wait, Duration::from_secs(1))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(limiter.check(other, now).is_ok());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let limiter = limiter(&["10.0.0.1", "10.0.0.2"]);
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let stranger: IpAddr = "203.0.113.9".parse().unwrap();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+
+        // Only trusted proxies can name the client
+        assert_eq!(limiter.client_ip(stranger, Some("192.0.2.1")), stranger);
+        assert_eq!(limiter.client_ip(proxy, None), proxy);
+        assert_eq!(limiter.client_ip(proxy, Some("192.0.2.1")), client);
+
+        // Entries added by the client itself are ignored
+        assert_eq!(
+            limiter.client_ip(proxy, Some("198.51.100.7, 192.0.2.1, 10.0.0.2")),
+            client
+        );
+        assert_eq!(
+            limiter.client_ip(proxy, Some("192.0.2.1, nonsense, 10.0.0.2")),
+            "10.0.0.2".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -1,8 +1,8 @@
-use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::time::Duration;
 
-use actix::Addr;
 use futures::future::{err, ok, Future};
 use hashbrown::HashMap;
 use parking_lot::RwLock;
@@ -14,14 +14,14 @@ use crate::graphql::schema::create_schema;
 use crate::graphql::schema::Schema;
 use crate::language::data_files::{get_data_files, DataFileType};
 use crate::language::grammar::{
-    list_preferences, AsyncGramchecker, GramcheckExecutor, GramcheckRequest, GramcheckResponse,
-};
-use crate::language::hyphenation::{
-    AsyncHyphenator, HyphenationExecutor, HyphenationRequest, HyphenationResponse,
-};
-use crate::language::speller::{
-    AsyncSpeller, DivvunSpellExecutor, SpellerRequest, SpellerResponse,
+    AsyncGramchecker, GramcheckRequest, GramcheckResponse, RestartPolicy,
 };
+use crate::language::hyphenation::{AsyncHyphenator, HyphenationRequest, HyphenationResponse};
+use crate::language::preferences::{list_preferences, GramcheckPreferences};
+use crate::language::speller::{AsyncSpeller, SpellerRequest, SpellerResponse};
+use crate::server::auth::Authenticator;
+use crate::server::rate_limit::IpRateLimiter;
+use crate::server::status::LanguageStatus;
 
 pub struct LanguageFunctions {
     pub spelling_suggestions:
@@ -43,6 +43,8 @@ pub trait LanguageSuggestions: Send + Sync {
     ) -> Box<dyn Future<Item = Self::Response, Error = ApiError>>;
     fn add(&self, language: &str, path: &str) -> Box<dyn Future<Item = (), Error = ApiError>>;
     fn remove(&self, language: &str) -> Box<dyn Future<Item = (), Error = ApiError>>;
+    fn has_language(&self, language: &str) -> bool;
+    fn status(&self) -> Vec<LanguageStatus>;
 }
 
 pub trait UnhoistFutureExt<U, E> {
@@ -67,43 +69,67 @@ pub struct InnerState {
     pub config: Config,
     pub graphql_schema: Schema,
     pub language_functions: LanguageFunctions,
-    pub gramcheck_preferences: Arc<RwLock<HashMap<String, BTreeMap<String, String>>>>,
+    pub gramcheck_preferences: Arc<RwLock<GramcheckPreferences>>,
+    /// Set by the watcher once it is watching the data directories
+    pub watcher_running: AtomicBool,
+    pub authenticator: Authenticator,
+    pub rate_limiter: IpRateLimiter,
 }
 
 pub fn create_state(config: &Config) -> State {
-    let grammar_data_files = get_data_files(config.data_file_dir.as_path(), DataFileType::Grammar)
-        .unwrap_or_else(|e| {
-            log::error!("Error getting grammar data files: {}", e);
-            vec![]
-        });
+    let grammar_data_files = enabled_data_files(config, DataFileType::Grammar);
 
     Arc::new(InnerState {
         config: config.clone(),
         graphql_schema: create_schema(),
         language_functions: LanguageFunctions {
             spelling_suggestions: Box::new(get_speller(config)),
-            grammar_suggestions: Box::new(get_gramchecker(&grammar_data_files)),
+            grammar_suggestions: Box::new(get_gramchecker(config, &grammar_data_files)),
             hyphenation_suggestions: Box::new(get_hyphenation(config)),
         },
         gramcheck_preferences: Arc::new(RwLock::new(get_gramcheck_preferences(
             &grammar_data_files,
         ))),
+        watcher_running: AtomicBool::new(false),
+        authenticator: Authenticator::new(&config.auth),
+        rate_limiter: IpRateLimiter::new(&config.rate_limit),
     })
 }
 
+/// Data files of the given type, or none if the service is disabled
+fn enabled_data_files(config: &Config, data_type: DataFileType) -> Vec<PathBuf> {
+    if !config.services.enabled(data_type) {
+        log::info!("The {} service is disabled", data_type.as_dir());
+        return vec![];
+    }
+
+    get_data_files(config.data_file_dir.as_path(), data_type)
+        .unwrap_or_else(|e| {
+            log::error!("Error getting {} data files: {}", data_type.as_dir(), e);
+            vec![]
+        })
+        .into_iter()
+        .filter(|file| match get_file_info(file) {
+            Some(file_info) if !config.language_enabled(file_info.stem) => {
+                log::info!(
+                    "Language {} is disabled, skipping {}",
+                    file_info.stem,
+                    file_info.path
+                );
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
 fn get_speller(config: &Config) -> AsyncSpeller {
-    let spelling_data_files =
-        get_data_files(config.data_file_dir.as_path(), DataFileType::Spelling).unwrap_or_else(
-            |e| {
-                log::error!("Error getting spelling data files: {}", e);
-                vec![]
-            },
-        );
+    let spelling_data_files = enabled_data_files(config, DataFileType::Spelling);
 
     let speller = AsyncSpeller {
-        spellers: Arc::new(RwLock::new(
-            HashMap::<String, Addr<DivvunSpellExecutor>>::new(),
-        )),
+        spellers: Arc::new(RwLock::new(HashMap::new())),
+        settings: config.speller.clone(),
+        languages: config.languages.clone(),
     };
 
     for file in spelling_data_files {
@@ -115,11 +141,16 @@ fn get_speller(config: &Config) -> AsyncSpeller {
     speller
 }
 
-fn get_gramchecker(grammar_data_files: &Vec<PathBuf>) -> AsyncGramchecker {
+fn get_gramchecker(config: &Config, grammar_data_files: &Vec<PathBuf>) -> AsyncGramchecker {
     let gramchecker = AsyncGramchecker {
-        gramcheckers: Arc::new(RwLock::new(
-            HashMap::<String, Addr<GramcheckExecutor>>::new(),
-        )),
+        gramcheckers: Arc::new(RwLock::new(HashMap::new())),
+        pool_size: config.grammar_pool_size,
+        timeout: Duration::from_millis(config.grammar_timeout_ms),
+        restart_policy: RestartPolicy {
+            backoff: Duration::from_millis(config.grammar_restart_backoff_ms),
+            max_restarts: config.grammar_max_restarts,
+        },
+        languages: config.languages.clone(),
     };
 
     for file in grammar_data_files {
@@ -132,18 +163,12 @@ fn get_gramchecker(grammar_data_files: &Vec<PathBuf>) -> AsyncGramchecker {
 }
 
 fn get_hyphenation(config: &Config) -> AsyncHyphenator {
-    let hyphenation_data_files =
-        get_data_files(config.data_file_dir.as_path(), DataFileType::Hyphenation).unwrap_or_else(
-            |e| {
-                log::error!("Error getting hyphenation data files: {}", e);
-                vec![]
-            },
-        );
+    let hyphenation_data_files = enabled_data_files(config, DataFileType::Hyphenation);
 
     let hyphenator = AsyncHyphenator {
-        hyphenators: Arc::new(RwLock::new(
-            HashMap::<String, Addr<HyphenationExecutor>>::new(),
-        )),
+        hyphenators: Arc::new(RwLock::new(HashMap::new())),
+        pool_size: config.hyphenation_pool_size,
+        languages: config.languages.clone(),
     };
 
     for file in hyphenation_data_files {
@@ -155,20 +180,180 @@ fn get_hyphenation(config: &Config) -> AsyncHyphenator {
     hyphenator
 }
 
-fn get_gramcheck_preferences(
-    grammar_data_files: &Vec<PathBuf>,
-) -> HashMap<String, BTreeMap<String, String>> {
+fn get_gramcheck_preferences(grammar_data_files: &Vec<PathBuf>) -> GramcheckPreferences {
     let gramcheck_preferences = grammar_data_files
         .into_iter()
         .map(|f| {
             let grammar_checker_path = f.to_str().unwrap();
             let lang_code = f.file_stem().unwrap().to_str().unwrap();
-            (
-                lang_code.into(),
-                list_preferences(grammar_checker_path).unwrap(),
-            )
+
+            let preferences = list_preferences(grammar_checker_path);
+            if let Err(e) = &preferences {
+                log::error!(
+                    "Failed to retrieve grammar preferences for {}: {}",
+                    lang_code,
+                    e
+                );
+            }
+
+            (lang_code.into(), preferences)
         })
         .collect();
 
     gramcheck_preferences
 }
+
+/// Fake language services and state for tests that don't run the real checkers
+#[cfg(test)]
+pub mod test_support {
+    use std::marker::PhantomData;
+
+    use super::*;
+    use crate::config::TomlConfig;
+
+    /// A service answering every request for any language with the same response
+    pub struct Fixed<Request, Response>(pub Response, pub PhantomData<fn(Request)>);
+
+    impl<Request, Response> LanguageSuggestions for Fixed<Request, Response>
+    where
+        Response: Clone + Send + Sync + 'static,
+    {
+        type Request = Request;
+        type Response = Response;
+
+        fn suggestions(
+            &self,
+            _: Self::Request,
+            _: &str,
+        ) -> Box<dyn Future<Item = Self::Response, Error = ApiError>> {
+            Box::new(ok(self.0.clone()))
+        }
+
+        fn add(&self, _: &str, _: &str) -> Box<dyn Future<Item = (), Error = ApiError>> {
+            Box::new(ok(()))
+        }
+
+        fn remove(&self, _: &str) -> Box<dyn Future<Item = (), Error = ApiError>> {
+            Box::new(ok(()))
+        }
+
+        fn has_language(&self, _: &str) -> bool {
+            true
+        }
+
+        fn status(&self) -> Vec<LanguageStatus> {
+            vec![]
+        }
+    }
+
+    /// A service without any languages
+    pub struct Missing<Request, Response>(pub PhantomData<fn(Request) -> Response>);
+
+    impl<Request, Response: 'static> LanguageSuggestions for Missing<Request, Response> {
+        type Request = Request;
+        type Response = Response;
+
+        fn suggestions(
+            &self,
+            _: Self::Request,
+            language: &str,
+        ) -> Box<dyn Future<Item = Self::Response, Error = ApiError>> {
+            Box::new(err(ApiError::LanguageNotFound(format!(
+                "No service available for language {}",
+                language
+            ))))
+        }
+
+        fn add(&self, _: &str, _: &str) -> Box<dyn Future<Item = (), Error = ApiError>> {
+            Box::new(ok(()))
+        }
+
+        fn remove(&self, _: &str) -> Box<dyn Future<Item = (), Error = ApiError>> {
+            Box::new(ok(()))
+        }
+
+        fn has_language(&self, _: &str) -> bool {
+            false
+        }
+
+        fn status(&self) -> Vec<LanguageStatus> {
+            vec![]
+        }
+    }
+
+    /// Language functions without any languages
+    pub fn missing_functions() -> LanguageFunctions {
+        LanguageFunctions {
+            spelling_suggestions: Box::new(Missing(PhantomData)),
+            grammar_suggestions: Box::new(Missing(PhantomData)),
+            hyphenation_suggestions: Box::new(Missing(PhantomData)),
+        }
+    }
+
+    /// The state of a server with the config, using the language functions. The data files are
+    /// looked for in the working directory unless the config names another one.
+    pub fn test_state(toml_config: TomlConfig, language_functions: LanguageFunctions) -> State {
+        let config = Config::from_toml(TomlConfig {
+            addr: Some("127.0.0.1:8080".to_owned()),
+            data_file_dir: toml_config
+                .data_file_dir
+                .or_else(|| Some(PathBuf::from("."))),
+            ..toml_config
+        })
+        .unwrap();
+
+        Arc::new(InnerState {
+            graphql_schema: create_schema(),
+            language_functions,
+            gramcheck_preferences: Arc::new(RwLock::new(GramcheckPreferences::default())),
+            watcher_running: AtomicBool::new(false),
+            authenticator: Authenticator::new(&config.auth),
+            rate_limiter: IpRateLimiter::new(&config.rate_limit),
+            config,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use crate::config::{LanguageSettings, TomlConfig};
+
+    #[test]
+    fn test_disabled_language_data_files() {
+        let data_file_dir = env::temp_dir().join(format!("divvun-api-data-{}", process::id()));
+        let spelling_dir = data_file_dir.join(DataFileType::Spelling.as_dir());
+        fs::create_dir_all(&spelling_dir).unwrap();
+        for file in &["se.zhfst", "sma.zhfst", "sme.txt"] {
+            fs::write(spelling_dir.join(file), "").unwrap();
+        }
+
+        let mut toml_config = TomlConfig {
+            addr: Some("127.0.0.1:8080".to_owned()),
+            data_file_dir: Some(data_file_dir.clone()),
+            ..Default::default()
+        };
+        toml_config.languages.insert(
+            "sma".to_owned(),
+            LanguageSettings {
+                enabled: false,
+                ..Default::default()
+            },
+        );
+        let mut config = Config::from_toml(toml_config).unwrap();
+
+        assert_eq!(
+            enabled_data_files(&config, DataFileType::Spelling),
+            vec![spelling_dir.join("se.zhfst")]
+        );
+
+        config.services.speller = false;
+        assert!(enabled_data_files(&config, DataFileType::Spelling).is_empty());
+
+        let _ = fs::remove_dir_all(data_file_dir);
+    }
+}
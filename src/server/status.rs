@@ -0,0 +1,93 @@
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{web, HttpResponse};
+use parking_lot::RwLock;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::server::state::State;
+
+pub type SharedWorkerStatus = Arc<RwLock<WorkerStatus>>;
+
+/// Runtime information about a single executor, updated by the executor itself
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct WorkerStatus {
+    /// PID of the child process, for executors that run one
+    pub pid: Option<u32>,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    /// The last lines the previous child process wrote to stderr before it died
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stderr: Vec<String>,
+    /// Whether the executor gave up restarting its failing child until its data file is replaced
+    pub unavailable: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LanguageStatus {
+    pub language: String,
+    /// The divvun-checker pipeline, for grammar checkers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<String>,
+    /// The UI language of the messages, for grammar checkers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ui_language: Option<String>,
+    pub path: String,
+    /// Seconds since the Unix epoch
+    pub loaded_at: u64,
+    pub workers: Vec<WorkerStatus>,
+}
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    pub speller: Vec<LanguageStatus>,
+    pub grammar: Vec<LanguageStatus>,
+    pub hyphenation: Vec<LanguageStatus>,
+}
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub data_dir_readable: bool,
+    pub watcher_running: bool,
+}
+
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn health_handler() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+pub fn ready_handler(state: web::Data<State>) -> HttpResponse {
+    let data_dir_readable = fs::read_dir(&state.config.data_file_dir).is_ok();
+    let watcher_running = state.watcher_running.load(Ordering::SeqCst);
+
+    let readiness = ReadinessResponse {
+        ready: data_dir_readable && watcher_running,
+        data_dir_readable,
+        watcher_running,
+    };
+
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+pub fn status_handler(state: web::Data<State>) -> HttpResponse {
+    let language_functions = &state.language_functions;
+
+    HttpResponse::Ok().json(StatusResponse {
+        speller: language_functions.spelling_suggestions.status(),
+        grammar: language_functions.grammar_suggestions.status(),
+        hyphenation: language_functions.hyphenation_suggestions.status(),
+    })
+}
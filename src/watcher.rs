@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
 use std::time::Duration;
 
@@ -6,13 +8,30 @@ use log::{error, info};
 use notify::Watcher as _;
 use notify::{watcher, DebouncedEvent, RecursiveMode};
 
-use crate::file_utils::get_file_info;
+use crate::file_utils::{get_file_info, FileInfo};
 use crate::language::data_files::{get_typed_data_dir, DataFileType};
-use crate::language::grammar::list_preferences;
+use crate::language::preferences::list_preferences;
+use crate::metrics::WATCHER_EVENTS;
 use crate::server::state::State;
 
 pub struct Watcher;
 
+/// Marks the watcher as stopped when its loop ends, whether it returned or panicked
+struct RunningFlag<'a>(&'a AtomicBool);
+
+impl<'a> RunningFlag<'a> {
+    fn set(running: &'a AtomicBool) -> Self {
+        running.store(true, Ordering::SeqCst);
+        RunningFlag(running)
+    }
+}
+
+impl Drop for RunningFlag<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
 impl Actor for Watcher {
     type Context = SyncContext<Self>;
 }
@@ -37,35 +56,21 @@ impl Handler<Start> for Watcher {
         let interval = state.config.watcher_interval_ms;
         let mut watcher = watcher(tx, Duration::from_millis(interval)).unwrap();
 
-        let dir = get_typed_data_dir(data_file_dir.as_path(), DataFileType::Grammar);
-        watcher
-            .watch(
-                get_typed_data_dir(data_file_dir.as_path(), DataFileType::Grammar),
-                RecursiveMode::NonRecursive,
-            )
-            .unwrap();
-        info!("Watching directory `{}` for grammar files", dir.display());
-
-        let dir = get_typed_data_dir(data_file_dir.as_path(), DataFileType::Spelling);
-        watcher
-            .watch(
-                get_typed_data_dir(data_file_dir.as_path(), DataFileType::Spelling),
-                RecursiveMode::NonRecursive,
-            )
-            .unwrap();
-        info!("Watching directory `{}` for speller files", dir.display());
-
-        let dir = get_typed_data_dir(data_file_dir.as_path(), DataFileType::Hyphenation);
-        watcher
-            .watch(
-                get_typed_data_dir(data_file_dir.as_path(), DataFileType::Hyphenation),
-                RecursiveMode::NonRecursive,
-            )
-            .unwrap();
-        info!(
-            "Watching directory `{}` for hyphenation files",
-            dir.display()
-        );
+        for (data_type, name) in &[
+            (DataFileType::Grammar, "grammar"),
+            (DataFileType::Spelling, "speller"),
+            (DataFileType::Hyphenation, "hyphenation"),
+        ] {
+            if !state.config.services.enabled(*data_type) {
+                continue;
+            }
+
+            let dir = get_typed_data_dir(data_file_dir.as_path(), *data_type);
+            watcher.watch(&dir, RecursiveMode::NonRecursive).unwrap();
+            info!("Watching directory `{}` for {} files", dir.display(), name);
+        }
+
+        let _running = RunningFlag::set(&state.watcher_running);
 
         loop {
             match rx.recv() {
@@ -73,15 +78,17 @@ impl Handler<Start> for Watcher {
                     DebouncedEvent::Create(path) => {
                         info!("Event {:?}", &event);
 
-                        if let Some(file_info) = get_file_info(path) {
+                        if let Some(file_info) = enabled_file_info(&state, path) {
+                            record_event("created", file_info.extension);
+
                             if file_info.extension == DataFileType::Grammar.as_ext() {
-                                let preferences = match list_preferences(file_info.path) {
-                                    Ok(preferences) => preferences,
-                                    Err(e) => {
-                                        error!("Failed to retrieve grammar preferences for {}: {}, ignoring file", e, file_info.stem);
-                                        continue;
-                                    }
-                                };
+                                let preferences = list_preferences(file_info.path);
+                                if let Err(e) = &preferences {
+                                    error!(
+                                        "Failed to retrieve grammar preferences for {}: {}",
+                                        file_info.stem, e
+                                    );
+                                }
 
                                 let grammar_checkers =
                                     &state.language_functions.grammar_suggestions;
@@ -101,7 +108,9 @@ impl Handler<Start> for Watcher {
                     DebouncedEvent::Remove(path) => {
                         info!("Event {:?}", &event);
 
-                        if let Some(file_info) = get_file_info(path) {
+                        if let Some(file_info) = enabled_file_info(&state, path) {
+                            record_event("removed", file_info.extension);
+
                             if file_info.extension == DataFileType::Grammar.as_ext() {
                                 let grammar_checkers =
                                     &state.language_functions.grammar_suggestions;
@@ -121,15 +130,17 @@ impl Handler<Start> for Watcher {
                     DebouncedEvent::Write(path) => {
                         info!("Event {:?}", &event);
 
-                        if let Some(file_info) = get_file_info(path) {
+                        if let Some(file_info) = enabled_file_info(&state, path) {
+                            record_event("written", file_info.extension);
+
                             if file_info.extension == DataFileType::Grammar.as_ext() {
-                                let preferences = match list_preferences(file_info.path) {
-                                    Ok(preferences) => preferences,
-                                    Err(e) => {
-                                        error!("Failed to retrieve grammar preferences for {}: {}, ignoring file", e, file_info.stem);
-                                        continue;
-                                    }
-                                };
+                                let preferences = list_preferences(file_info.path);
+                                if let Err(e) = &preferences {
+                                    error!(
+                                        "Failed to retrieve grammar preferences for {}: {}",
+                                        file_info.stem, e
+                                    );
+                                }
 
                                 let grammar_checkers =
                                     &state.language_functions.grammar_suggestions;
@@ -155,8 +166,66 @@ impl Handler<Start> for Watcher {
                     }
                     _ => info!("Event {:?}", &event),
                 },
-                Err(e) => error!("Watch error: {:?}", e),
+                Err(e) => {
+                    // The watcher is gone, so no more events will come
+                    error!("Watch error: {:?}", e);
+                    return Err(());
+                }
             }
         }
     }
 }
+
+/// Information about the changed file, unless it belongs to a disabled language
+fn enabled_file_info<'a>(state: &State, path: &'a PathBuf) -> Option<FileInfo<'a>> {
+    get_file_info(path).filter(|file_info| {
+        let enabled = state.config.language_enabled(file_info.stem);
+        if !enabled {
+            info!("Language {} is disabled, ignoring change", file_info.stem);
+        }
+        enabled
+    })
+}
+
+fn record_event(event: &str, extension: &str) {
+    let data_type = [
+        DataFileType::Grammar,
+        DataFileType::Spelling,
+        DataFileType::Hyphenation,
+    ]
+    .iter()
+    .find(|data_type| data_type.as_ext() == extension)
+    .map(|data_type| data_type.as_dir())
+    .unwrap_or("other");
+
+    WATCHER_EVENTS.with_label_values(&[event, data_type]).inc();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{LanguageSettings, TomlConfig};
+    use crate::server::state::test_support::{missing_functions, test_state};
+
+    #[test]
+    fn test_disabled_language_changes() {
+        let mut toml_config = TomlConfig::default();
+        toml_config.languages.insert(
+            "sma".to_owned(),
+            LanguageSettings {
+                enabled: false,
+                ..Default::default()
+            },
+        );
+        let state = test_state(toml_config, missing_functions());
+
+        let enabled = PathBuf::from("data/spelling/se.zhfst");
+        let disabled = PathBuf::from("data/spelling/sma.zhfst");
+
+        assert_eq!(
+            enabled_file_info(&state, &enabled).map(|file_info| file_info.stem),
+            Some("se")
+        );
+        assert!(enabled_file_info(&state, &disabled).is_none());
+    }
+}
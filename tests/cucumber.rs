@@ -8,8 +8,8 @@ use std::path::PathBuf;
 use std::{env, thread, time};
 
 use divvun_api::config::Config;
-use divvun_api::error::ApiError;
-use divvun_api::init::{init_config, init_system};
+use divvun_api::error::ErrorResponse;
+use divvun_api::init::{init_cli, init_config, init_system};
 use divvun_api::language::grammar::GramcheckResponse;
 use divvun_api::language::hyphenation::HyphenationResponse;
 use divvun_api::language::speller::SpellerResponse;
@@ -27,20 +27,15 @@ pub struct MyWorld {
     speller_response: Option<SpellerResponse>,
     grammar_response: Option<GramcheckResponse>,
     hyphenation_response: Option<HyphenationResponse>,
-    api_error: Option<ApiError>,
+    api_error: Option<ErrorResponse>,
+    status: Option<u16>,
 }
 
 impl cucumber_rust::World for MyWorld {}
 
 impl Default for MyWorld {
     fn default() -> MyWorld {
-        let toml_config = init_config();
-
-        let config = Config {
-            addr: toml_config.addr,
-            data_file_dir: PathBuf::from(TEST_DATA_FILES),
-            watcher_interval_ms: TEST_WATCHER_INTERVAL,
-        };
+        let config = test_config();
 
         // This function is called every time a new scenario is started
         MyWorld {
@@ -50,25 +45,28 @@ impl Default for MyWorld {
             grammar_response: None,
             hyphenation_response: None,
             api_error: None,
+            status: None,
         }
     }
 }
 
+fn test_config() -> Config {
+    let mut toml_config = init_config(&init_cli()).unwrap();
+    toml_config.data_file_dir = Some(PathBuf::from(TEST_DATA_FILES));
+    toml_config.watcher_interval_ms = Some(TEST_WATCHER_INTERVAL);
+
+    Config::from_toml(toml_config).unwrap()
+}
+
 // A setup function to be called before everything else
 fn setup() {
     env::set_var("RUST_LOG", "info");
     env_logger::init();
 
-    let toml_config = init_config();
-
-    let config = Config {
-        addr: toml_config.addr,
-        data_file_dir: PathBuf::from(TEST_DATA_FILES),
-        watcher_interval_ms: TEST_WATCHER_INTERVAL,
-    };
+    let config = test_config();
 
     std::thread::spawn(move || {
-        let (_app, system) = init_system(&config);
+        let (_app, system) = init_system(&config).unwrap();
 
         system.run().unwrap();
     });
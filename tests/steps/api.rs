@@ -1,4 +1,4 @@
-use divvun_api::error::ApiError;
+use divvun_api::error::ErrorResponse;
 use divvun_api::language::grammar::GramcheckResponse;
 use divvun_api::language::hyphenation::HyphenationResponse;
 use divvun_api::language::speller::SpellerResponse;
@@ -32,6 +32,20 @@ steps!(crate::MyWorld => {
         }}));
     };
 
+    then regex r"^I get back a JSON object with the status `([^`]*)`$" (String) |world, status, _step| {
+        assert_eq!(&world.json, &json!({ "status": status }));
+    };
+
+    then regex r"^I get back a JSON object listing `([^`]*)` for every service$" (String) |world, language, _step| {
+        for service in &["speller", "grammar", "hyphenation"] {
+            let languages = world.json[service].as_array().unwrap();
+            assert!(
+                languages.iter().any(|status| status["language"] == json!(language)),
+                "{} is not loaded for {}", language, service
+            );
+        }
+    };
+
     when regex r"^I go to the endpoint `([^`]*)` with appropriate data$" |world, matches, _step| {
         let client = reqwest::Client::new();
         let url = format!("http://{}{}", &world.config.addr, matches[1]);
@@ -56,6 +70,42 @@ steps!(crate::MyWorld => {
         };
     };
 
+    when regex r"^I go to the endpoint `([^`]*)` with texts for `([^`]*)` and `([^`]*)`$" (String, String, String) |world, endpoint, language0, language1, _step| {
+        let client = reqwest::Client::new();
+        let url = format!("http://{}{}", &world.config.addr, endpoint);
+
+        let mut response = client.post(&url).json(&json!([
+            {"id": "first", "language": language0, "services": ["speller", "hyphenation"], "text": "pákhat"},
+            {"id": "second", "language": language1, "services": ["grammar"], "text": "sup  ney"}
+        ])).send().unwrap();
+
+        world.status = Some(response.status().as_u16());
+        world.json = response.json().unwrap();
+    };
+
+    then regex r"^I get back a result for each item, with an error for `([^`]*)`$" (String) |world, language, _step| {
+        assert_eq!(world.status, Some(200));
+
+        let results = world.json["results"].as_array().unwrap();
+        assert_eq!(results.len(), 2);
+
+        let first = &results[0];
+        assert_eq!(first["id"], json!("first"));
+        assert_eq!(first["speller"]["results"][0]["word"], json!("pákhat"));
+        assert_eq!(first["hyphenation"]["results"][0]["hyphenations"][0]["value"], json!("pák^hat"));
+        assert_eq!(first["grammar"], json!(null));
+        assert_eq!(first["errors"], json!([]));
+
+        let second = &results[1];
+        assert_eq!(second["id"], json!("second"));
+        assert_eq!(second["grammar"], json!(null));
+        assert_eq!(second["errors"], json!([{
+            "service": "grammar",
+            "code": "language_not_found",
+            "message": format!("No grammar checker available for language {}", language)
+        }]));
+    };
+
     then "I get back a SpellerResponse with suggestions for each word" |world, _step| {
         let response = &world.speller_response.clone().unwrap();
         assert_eq!(response.text, "oainá páhkat");
@@ -70,12 +120,37 @@ steps!(crate::MyWorld => {
 
         let pahkat_res = &response.results[1];
         assert_eq!(pahkat_res.word, "páhkat");
+        assert_eq!(pahkat_res.start_byte, 7);
+        assert_eq!(pahkat_res.end_byte, 14);
+        assert_eq!(pahkat_res.start_char, 6);
+        assert_eq!(pahkat_res.end_char, 12);
+        assert_eq!(pahkat_res.start_utf16, 6);
+        assert_eq!(pahkat_res.end_utf16, 12);
         assert_eq!(pahkat_res.is_correct, true);
         assert_eq!(pahkat_res.suggestions.len() > 3, true);
         assert_eq!(pahkat_res.suggestions[0].value, "dahkat");
         assert_eq!(pahkat_res.suggestions[0].weight, 14.0126953125);
     };
 
+    when regex r"^I go to the endpoint `([^`]*)` asking only for misspelled words$" (String) |world, endpoint, _step| {
+        let client = reqwest::Client::new();
+        let url = format!("http://{}{}", &world.config.addr, endpoint);
+
+        let response: SpellerResponse = client.post(&url).json(&json!({"text": "páhkat oainá", "only_misspelled": true})).send().unwrap().json().unwrap();
+        world.speller_response = Some(response);
+    };
+
+    then "I get back a SpellerResponse with only the misspelled word" |world, _step| {
+        let response = &world.speller_response.clone().unwrap();
+        assert_eq!(response.results.len(), 1);
+
+        let oaina_res = &response.results[0];
+        assert_eq!(oaina_res.word, "oainá");
+        assert_eq!(oaina_res.is_correct, false);
+        assert_eq!(oaina_res.start_char, 7);
+        assert_eq!(oaina_res.suggestions[0].value, "oaidná");
+    };
+
     then regex r"^I get back a GramcheckResponse with `([^`]*)` and `([^`]*)` error codes$" (String, String) |world, code0, code1, _step| {
         let response = &world.grammar_response.clone().unwrap();
         assert_eq!(response.text, "sup  ney");
@@ -98,6 +173,44 @@ steps!(crate::MyWorld => {
         assert_ne!(err1.suggestions.len(), 0);
     };
 
+    when regex r"^I go to the endpoint `([^`]*)` with multi-line data$" (String) |world, endpoint, _step| {
+        let client = reqwest::Client::new();
+        let url = format!("http://{}{}", &world.config.addr, endpoint);
+
+        let response: GramcheckResponse = client.post(&url).json(&json!({"text": "sup  ney\n\npákhat"})).send().unwrap().json().unwrap();
+        world.grammar_response = Some(response);
+    };
+
+    then "I get back a GramcheckResponse with errors offset to each line" |world, _step| {
+        let response = &world.grammar_response.clone().unwrap();
+        assert_eq!(response.text, "sup  ney\n\npákhat");
+
+        let errs = &response.errs;
+        assert_eq!(errs.len(), 3);
+
+        let last = &errs[2];
+        assert_eq!(last.error_text, "pákhat");
+        assert_eq!(last.error_code, "typo");
+        assert_eq!(last.start_index, 10);
+        assert_eq!(last.end_index, 16);
+    };
+
+    when regex r"^I go to the endpoint `([^`]*)` ignoring the `([^`]*)` error tag$" (String, String) |world, endpoint, tag, _step| {
+        let client = reqwest::Client::new();
+        let url = format!("http://{}{}", &world.config.addr, endpoint);
+
+        let response: GramcheckResponse = client.post(&url).json(&json!({"text": "sup  ney", "ignore_tags": [tag]})).send().unwrap().json().unwrap();
+        world.grammar_response = Some(response);
+    };
+
+    then regex r"^I get back a GramcheckResponse with only the `([^`]*)` error code$" (String) |world, code, _step| {
+        let response = &world.grammar_response.clone().unwrap();
+
+        let errs = &response.errs;
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].error_code, code);
+    };
+
     then "I get back the correct HyphenationResponse" |world, _step| {
         let response = &world.hyphenation_response.clone().unwrap();
 
@@ -115,16 +228,18 @@ steps!(crate::MyWorld => {
         let client = reqwest::Client::new();
         let url = format!("http://{}{}", &world.config.addr, endpoint);
 
-        let response: ApiError = client.post(&url).json(&json!({"text": "doesn'tmatter"})).send().unwrap().json().unwrap();
-        world.api_error = Some(response);
+        let mut response = client.post(&url).json(&json!({"text": "doesn'tmatter"})).send().unwrap();
+        world.status = Some(response.status().as_u16());
+        world.api_error = Some(response.json::<ErrorResponse>().unwrap());
     };
 
     when regex r"^I go to the endpoint `(/grammar/.*)` for not loaded language$" (String) |world, endpoint, _step| {
         let client = reqwest::Client::new();
         let url = format!("http://{}{}", &world.config.addr, endpoint);
 
-        let response: ApiError = client.post(&url).json(&json!({"text": "doesn't matter"})).send().unwrap().json().unwrap();
-        world.api_error = Some(response);
+        let mut response = client.post(&url).json(&json!({"text": "doesn't matter"})).send().unwrap();
+        world.status = Some(response.status().as_u16());
+        world.api_error = Some(response.json::<ErrorResponse>().unwrap());
     };
 
     then regex r"^I get back an ApiError with the message `([^`]*)`$" (String) |world, message, _step| {
@@ -132,6 +247,12 @@ steps!(crate::MyWorld => {
         assert_eq!(error.message, message);
     };
 
+    then regex r"^the error has the status `(\d+)` and the code `([^`]*)`$" (u16, String) |world, status, code, _step| {
+        let error = &world.api_error.clone().unwrap();
+        assert_eq!(world.status, Some(status));
+        assert_eq!(error.code, code);
+    };
+
     when regex r"^I go to the endpoint `([^`]*)` with an appropriate GraphQL query$" (String) |world, endpoint, _step| {
         let client = reqwest::Client::new();
         let url = format!("http://{}{}", &world.config.addr, endpoint);